@@ -0,0 +1,45 @@
+//! Calling methods with your own param and result types, for firmware features the library
+//! doesn't cover. Run with the camera's address, e.g.
+//!
+//!     cargo run --example custom_method -- ws://192.168.80.80:12345/8086
+
+use anyhow::Result;
+use panonoctl_rs::PanonoClient;
+use serde::{Deserialize, Serialize};
+
+/// Only the parts of `get_status` this tool cares about, including a field newer firmware adds
+#[derive(Debug, Deserialize)]
+struct Status {
+    firmware_version: String,
+    battery_level: Option<u8>,
+}
+
+#[derive(Debug, Serialize)]
+struct GetOption<'a> {
+    name: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct OptionValue {
+    name: String,
+    value: serde_json::Value,
+}
+
+fn main() -> Result<()> {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "ws://192.168.80.80:12345/8086".into());
+    let mut client = PanonoClient::connect(&address, false)?;
+    client.auth("custom-method-example".into(), "test".into(), None)?;
+
+    let status: Status = client.call("get_status", ())?;
+    println!(
+        "firmware {}, battery {:?}",
+        status.firmware_version, status.battery_level
+    );
+
+    let option: OptionValue = client.call("get_option", GetOption { name: "ISO" })?;
+    println!("{} = {}", option.name, option.value);
+
+    client.close()
+}
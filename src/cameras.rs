@@ -0,0 +1,333 @@
+use anyhow::{bail, Context, Result};
+use panonoctl_rs::{AuthRejected, PanonoClient, ResponseStatus};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::token::{SavedToken, TokenStore};
+
+/// The camera closes connections that leave its keepalive pings unanswered, which happens after a
+/// few minutes of sitting idle at the REPL prompt since nothing reads from the socket then. Check
+/// for pending frames in the background whenever no request is in flight so pings are answered
+/// promptly.
+fn spawn_keepalive(client: Arc<Mutex<PanonoClient>>) {
+    spawn_periodic(
+        client,
+        Duration::from_secs(1),
+        "keepalive",
+        PanonoClient::poll,
+    );
+}
+
+/// Fetch the status every `interval` so the capture availability and battery level shown in the
+/// prompt stay current
+pub fn spawn_status_poller(client: Arc<Mutex<PanonoClient>>, interval: Duration) {
+    spawn_periodic(client, interval, "status polling", |client| {
+        client.status().map(|_| ())
+    });
+}
+
+/// Run `task` every `interval` until the connection is closed. Rounds where a command holds the
+/// connection are skipped, it is already reading from the socket then. A failure is logged and
+/// tried again next round, since reconnecting or the network coming back may well fix it.
+fn spawn_periodic(
+    client: Arc<Mutex<PanonoClient>>,
+    interval: Duration,
+    name: &'static str,
+    task: impl Fn(&mut PanonoClient) -> Result<()> + Send + 'static,
+) {
+    thread::spawn(move || {
+        // only the first of a run of failures is logged so a dead connection doesn't flood stderr
+        let mut failing = false;
+        loop {
+            thread::sleep(interval);
+            let Ok(mut client) = client.try_lock() else {
+                continue;
+            };
+            if client.is_closed() {
+                break;
+            }
+            match task(&mut client) {
+                Ok(()) => failing = false,
+                Err(e) if !failing => {
+                    log::warn!("{name} failed, retrying: {e:#}");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
+
+/// Everything needed to connect and authenticate to a camera, taken from the command line
+#[derive(Debug, Clone)]
+pub struct Connector {
+    pub insecure_tls: bool,
+    pub reconnect: bool,
+    pub timeout: Option<Duration>,
+    pub trace_file: Option<PathBuf>,
+    pub device_name: String,
+    pub force: String,
+    pub save_token: bool,
+    /// Throw away the saved token before authenticating, for when the camera no longer accepts it
+    pub forget_token: bool,
+}
+
+impl Connector {
+    /// Connect and authenticate, reusing and saving the auth token for `address`
+    pub fn open(&self, address: &str) -> Result<(PanonoClient, ResponseStatus)> {
+        let mut client = PanonoClient::connect(address, self.insecure_tls)?;
+        client.set_reconnect(self.reconnect);
+        client.set_timeout(self.timeout)?;
+        if let Some(path) = &self.trace_file {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            client.set_trace(Some(Box::new(file)));
+        }
+
+        let mut tokens = TokenStore::load().unwrap_or_else(|e| {
+            eprintln!("Ignoring saved auth tokens: {e:#}");
+            TokenStore::default()
+        });
+        if self.forget_token && tokens.remove_by_address(address) {
+            if let Err(e) = tokens.save() {
+                eprintln!("Failed to forget the saved auth token: {e:#}");
+            }
+        }
+        let auth = client.auth(
+            self.device_name.clone(),
+            self.force.clone(),
+            tokens
+                .find_by_address(address)
+                .map(|t| t.auth_token.clone()),
+        );
+        let auth = match auth {
+            Err(e) if e.downcast_ref::<AuthRejected>().is_some() => Err(e.context(
+                "Another client, such as the Panono phone app, seems to be connected to the camera. \
+                 Disconnect it or use --force to take over its session",
+            )),
+            auth => auth,
+        }?;
+        if self.save_token {
+            tokens.insert(
+                auth.device_id.clone(),
+                SavedToken {
+                    address: address.to_string(),
+                    auth_token: auth.auth_token.clone(),
+                },
+            );
+            if let Err(e) = tokens.save() {
+                eprintln!("Failed to save auth token: {e:#}");
+            }
+        }
+        Ok((client, auth))
+    }
+}
+
+/// A connected camera, numbered from 1 in the order they were connected
+struct Slot {
+    number: usize,
+    address: String,
+    client: Arc<Mutex<PanonoClient>>,
+}
+
+/// The cameras connected to in this session. Commands all hold on to the first slot, so switching
+/// cameras swaps the chosen connection into it rather than handing out a new client.
+pub struct Cameras {
+    slots: Vec<Slot>,
+}
+
+impl Cameras {
+    pub fn new(client: PanonoClient, address: String) -> Self {
+        let mut cameras = Self { slots: vec![] };
+        cameras.add(client, address);
+        cameras
+    }
+
+    /// The slot holding whichever camera is in use
+    pub fn active(&self) -> Arc<Mutex<PanonoClient>> {
+        self.slots[0].client.clone()
+    }
+
+    pub fn active_number(&self) -> usize {
+        self.slots[0].number
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Add another camera without switching to it, returning its number
+    pub fn add(&mut self, client: PanonoClient, address: String) -> usize {
+        let client = Arc::new(Mutex::new(client));
+        spawn_keepalive(client.clone());
+        let number = self.slots.len() + 1;
+        self.slots.push(Slot {
+            number,
+            address,
+            client,
+        });
+        number
+    }
+
+    /// Make camera `number` the one commands talk to
+    pub fn switch(&mut self, number: usize) -> Result<()> {
+        let Some(i) = self.slots.iter().position(|s| s.number == number) else {
+            bail!(
+                "No camera {number}, expected a number between 1 and {}",
+                self.slots.len()
+            );
+        };
+        if i == 0 {
+            return Ok(());
+        }
+        let (active, rest) = self.slots.split_at_mut(1);
+        let (active, other) = (&mut active[0], &mut rest[i - 1]);
+        std::mem::swap(
+            &mut *active.client.lock().unwrap(),
+            &mut *other.client.lock().unwrap(),
+        );
+        std::mem::swap(&mut active.number, &mut other.number);
+        std::mem::swap(&mut active.address, &mut other.address);
+        Ok(())
+    }
+
+    /// Number and address of every camera in the order they were connected
+    pub fn list(&self) -> Vec<(usize, &str)> {
+        let mut list = self
+            .slots
+            .iter()
+            .map(|s| (s.number, s.address.as_str()))
+            .collect::<Vec<_>>();
+        list.sort();
+        list
+    }
+
+    /// Run `f` against every camera in turn, stopping at the first error
+    pub fn for_each(
+        &self,
+        mut f: impl FnMut(usize, &str, &mut PanonoClient) -> Result<()>,
+    ) -> Result<()> {
+        let mut slots = self.slots.iter().collect::<Vec<_>>();
+        slots.sort_by_key(|s| s.number);
+        for slot in slots {
+            f(slot.number, &slot.address, &mut slot.client.lock().unwrap())?;
+        }
+        Ok(())
+    }
+
+    pub fn close_all(&self) {
+        for slot in &self.slots {
+            if let Err(e) = slot.client.lock().unwrap().close() {
+                eprintln!("Failed to close connection to {}: {e:#}", slot.address);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Accept a single websocket connection on `host`, returning the address to reach it at
+    fn camera(host: &str) -> Option<(String, thread::JoinHandle<()>)> {
+        let mut server = websocket::sync::Server::bind(format!("{host}:0")).ok()?;
+        let port = server.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let client = server
+                .accept()
+                .ok()
+                .unwrap()
+                .use_protocol("rust-websocket")
+                .accept()
+                .ok()
+                .unwrap();
+            // hold the connection open until the other end goes away
+            let _ = client.into_stream();
+        });
+        Some((format!("ws://{host}:{port}/8086"), handle))
+    }
+
+    #[test]
+    fn status_poller() {
+        let camera = panonoctl_rs::mock::MockCamera::start().unwrap();
+        let client = Arc::new(Mutex::new(
+            PanonoClient::connect(camera.address(), false).unwrap(),
+        ));
+        assert_eq!(client.lock().unwrap().battery_level(), None);
+        camera.reply(
+            "get_status",
+            panonoctl_rs::mock::Reply::Result(serde_json::json!({
+                "auth_token": "",
+                "battery_level": 64,
+                "capture_available": false,
+                "current_time": "2017-06-03T14:21:07Z",
+                "device_id": "",
+                "firmware_update_url": "",
+                "firmware_version": "",
+                "is_auth": true,
+                "serial_number": "",
+                "storage": {},
+                "update_ready": false
+            })),
+        );
+        // a failed round doesn't stop polling
+        camera.reply_once(
+            "get_status",
+            panonoctl_rs::mock::Reply::Error {
+                code: 500,
+                message: "busy".into(),
+            },
+        );
+        spawn_status_poller(client.clone(), Duration::from_millis(20));
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while client.lock().unwrap().battery_level().is_none() {
+            assert!(std::time::Instant::now() < deadline, "never polled");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let client = client.lock().unwrap();
+        assert_eq!(client.battery_level(), Some(64));
+        assert_eq!(client.capture_available(), Some(false));
+    }
+
+    #[test]
+    fn switch() {
+        let (Some((first, a)), Some((second, b))) = (camera("127.0.0.1"), camera("127.0.0.2"))
+        else {
+            eprintln!("127.0.0.2 unavailable, skipping");
+            return;
+        };
+        let mut cameras =
+            Cameras::new(PanonoClient::connect(&first, false).unwrap(), first.clone());
+        let active = cameras.active();
+        assert_eq!(
+            cameras.add(
+                PanonoClient::connect(&second, false).unwrap(),
+                second.clone()
+            ),
+            2
+        );
+        assert_eq!(cameras.active_number(), 1);
+
+        cameras.switch(2).unwrap();
+        assert_eq!(cameras.active_number(), 2);
+        // commands keep the handle they were given, which now talks to the second camera
+        assert_eq!(
+            active.lock().unwrap().web_ui_url().unwrap(),
+            "http://127.0.0.2/"
+        );
+        assert_eq!(cameras.list(), [(1, first.as_str()), (2, second.as_str())]);
+        assert!(cameras.switch(3).is_err());
+
+        cameras.close_all();
+        a.join().unwrap();
+        b.join().unwrap();
+    }
+}
@@ -0,0 +1,974 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::value::RawValue;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{self, BufReader, Cursor, Read, Write},
+    net::{Shutdown, TcpStream},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use websocket::{
+    native_tls::TlsConnector,
+    receiver::Receiver,
+    sender::Sender,
+    stream::sync::{AsTcpStream, NetworkStream},
+    url::Url,
+    ws::{self, Receiver as _, Sender as _},
+    ClientBuilder, Message, OwnedMessage, WebSocketError, WebSocketResult,
+};
+
+use crate::protocol::*;
+
+type Stream = Box<dyn NetworkStream + Send>;
+
+/// An open websocket. This is `websocket::sync::Client` taken apart, since the client doesn't
+/// expose its read buffer and `poll` needs to know whether a frame is waiting there. Whatever the
+/// handshake read past its response is read first.
+struct Ws {
+    stream: BufReader<io::Chain<Cursor<Vec<u8>>, Stream>>,
+    receiver: Receiver,
+    sender: Sender,
+}
+
+impl Ws {
+    fn tcp(&self) -> &TcpStream {
+        self.stream.get_ref().get_ref().1.as_tcp()
+    }
+
+    fn send_message(&mut self, message: &impl ws::Message) -> WebSocketResult<()> {
+        self.sender
+            .send_message(self.stream.get_mut().get_mut().1, message)
+    }
+
+    fn recv_message(&mut self) -> WebSocketResult<OwnedMessage> {
+        self.receiver.recv_message(&mut self.stream)
+    }
+
+    /// Whether a frame is buffered or has started arriving, so reading one won't wait on an idle
+    /// connection. Once the first byte is in, the rest of the frame is read with the usual timeout.
+    fn has_pending(&self) -> io::Result<bool> {
+        let handshake = self.stream.get_ref().get_ref().0;
+        if !self.stream.buffer().is_empty()
+            || handshake.position() < handshake.get_ref().len() as u64
+        {
+            return Ok(true);
+        }
+        let tcp = self.tcp();
+        tcp.set_nonblocking(true)?;
+        let res = tcp.peek(&mut [0]);
+        tcp.set_nonblocking(false)?;
+        match res {
+            // 0 bytes means the camera closed the connection, which reading will report
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The camera closed the websocket
+#[derive(Debug)]
+pub struct ConnectionClosed;
+
+impl std::fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Websocket closed")
+    }
+}
+
+impl std::error::Error for ConnectionClosed {}
+
+/// The connection was lost and could not be restored, either because reconnecting is disabled or
+/// because it failed too. Further requests will fail the same way.
+#[derive(Debug)]
+pub struct Disconnected;
+
+impl std::fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Lost the connection to the camera")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// The camera answered auth without authenticating the client, usually because another client
+/// such as the phone app is connected
+#[derive(Debug)]
+pub struct AuthRejected;
+
+impl std::fmt::Display for AuthRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The camera refused to authenticate this client")
+    }
+}
+
+impl std::error::Error for AuthRejected {}
+
+/// Turn both ways the camera refuses auth, an error response or a status with `is_auth` unset,
+/// into `AuthRejected`. Otherwise commands fail later with errors that don't point at the cause.
+fn check_auth(res: Result<ResponseStatus>) -> Result<ResponseStatus> {
+    match res {
+        Ok(status) if !status.is_auth => bail!(AuthRejected),
+        Err(e) if e.downcast_ref::<ResponseError>().is_some() => Err(e.context(AuthRejected)),
+        res => res,
+    }
+}
+
+/// JSON-RPC connection to a camera
+pub struct PanonoClient {
+    ws: Ws,
+    address: String,
+    insecure_tls: bool,
+    timeout: Option<Duration>,
+    /// Whether to reconnect and retry once when the connection drops mid-request
+    reconnect: bool,
+    /// Last successful auth request, replayed after reconnecting
+    auth: Option<Method>,
+    /// Id of the last request sent
+    req_id: u32,
+    /// Requests awaiting a response, and the response once it arrives
+    in_flight: HashMap<u32, Option<Result<Box<RawValue>, ResponseError>>>,
+    /// Latest known `capture_available`, kept up to date by `status_update` notifications
+    capture_available: Option<bool>,
+    /// Battery level from the last status, on firmware that reports it
+    battery_level: Option<u8>,
+    /// Text of the last packet that answered a request, successfully or not
+    last_response: Option<String>,
+    /// Start of a packet whose remainder hasn't arrived yet
+    packets: PacketBuffer,
+    /// Set by `close`, so a background `poll` doesn't reconnect afterwards
+    closed: bool,
+    /// Where to record every raw text frame sent and received
+    trace: Option<Box<dyn Write + Send>>,
+}
+
+const ADDRESS_EXAMPLE: &str = "ws://192.168.80.80:12345/8086";
+
+/// Check the address is a websocket URL with a host and port, explaining the expected format if not
+fn parse_address(address: &str) -> Result<Url> {
+    let url = Url::parse(address).with_context(|| {
+        format!("Invalid camera address {address:?}, expected a URL like {ADDRESS_EXAMPLE}")
+    })?;
+    if !matches!(url.scheme(), "ws" | "wss") {
+        bail!(
+            "Camera address {address:?} must start with ws:// (or wss:// for TLS), e.g. {ADDRESS_EXAMPLE}"
+        );
+    }
+    if url.host().is_none() || url.port().is_none() {
+        bail!("Camera address {address:?} must include a host and port, e.g. {ADDRESS_EXAMPLE}");
+    }
+    Ok(url)
+}
+
+/// Open the websocket, using TLS if the address is a `wss://` URL
+fn connect_ws(address: &str, insecure_tls: bool) -> Result<Ws> {
+    let url = parse_address(address)?;
+    let mut builder = ClientBuilder::from_url(&url).add_protocol("rust-websocket");
+    // `connect` picks a plain or TLS stream based on the URL scheme, the connector is only used for
+    // the latter
+    let tls = TlsConnector::builder()
+        .danger_accept_invalid_certs(insecure_tls)
+        .danger_accept_invalid_hostnames(insecure_tls)
+        .build()?;
+    let ws = builder.connect(Some(tls)).map_err(|e| {
+        let hint = if is_unreachable(&e) {
+            ", check the camera is on and reachable from this network"
+        } else {
+            ""
+        };
+        anyhow::Error::new(e).context(format!("Failed to connect to {address}{hint}"))
+    })?;
+    info!("connected to {address}");
+    // the handshake may have read past the response into the first frames, so keep its buffer
+    let (stream, buffered) = ws.into_stream();
+    let handshake = match buffered {
+        Some((buf, pos, cap)) => buf[pos..cap].to_vec(),
+        None => Vec::new(),
+    };
+    Ok(Ws {
+        stream: BufReader::new(Cursor::new(handshake).chain(stream)),
+        receiver: Receiver::new(false),
+        sender: Sender::new(true),
+    })
+}
+
+impl PanonoClient {
+    /// Connect to the camera, using TLS if the address is a `wss://` URL. `insecure_tls` skips
+    /// certificate verification for cameras with self-signed certs.
+    pub fn connect(address: &str, insecure_tls: bool) -> Result<Self> {
+        Ok(Self {
+            ws: connect_ws(address, insecure_tls)?,
+            address: address.to_string(),
+            insecure_tls,
+            timeout: None,
+            reconnect: false,
+            auth: None,
+            req_id: 0,
+            in_flight: HashMap::new(),
+            capture_available: None,
+            battery_level: None,
+            last_response: None,
+            packets: PacketBuffer::default(),
+            closed: false,
+            trace: None,
+        })
+    }
+
+    /// Record every raw text frame sent and received to `trace`, one per line prefixed with a unix
+    /// timestamp and `>` for outgoing or `<` for incoming
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write + Send>>) {
+        self.trace = trace;
+    }
+
+    /// How long to wait for a response before giving up, `None` to wait forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.ws.tcp().set_read_timeout(timeout)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Transparently reconnect, re-auth, and retry the request once if the connection drops
+    pub fn set_reconnect(&mut self, reconnect: bool) {
+        self.reconnect = reconnect;
+    }
+
+    /// Open a fresh websocket to the same address and repeat the last auth
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.ws = connect_ws(&self.address, self.insecure_tls)?;
+        // nothing sent on the old connection will be answered on the new one
+        self.in_flight.clear();
+        self.packets.clear();
+        self.ws.tcp().set_read_timeout(self.timeout)?;
+        if let Some(auth) = self.auth.clone() {
+            let res = check_auth(self.request(&auth))?;
+            self.update_status(&res);
+        }
+        Ok(())
+    }
+
+    /// Whether `close` was called, after which nothing more is read from the connection
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Send a close frame and shut the connection down. Without this the camera may keep treating
+    /// the client as attached and refuse the next connection.
+    pub fn close(&mut self) -> Result<()> {
+        info!("closing connection");
+        self.closed = true;
+        self.ws.send_message(&Message::close())?;
+        self.ws.tcp().shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    /// The camera's HTTP interface, served from the same host as the websocket
+    pub fn web_ui_url(&self) -> Result<String> {
+        let url = parse_address(&self.address)?;
+        let scheme = if url.scheme() == "wss" {
+            "https"
+        } else {
+            "http"
+        };
+        Ok(format!(
+            "{scheme}://{}/",
+            url.host_str().unwrap_or_default()
+        ))
+    }
+
+    /// Latest known capture availability, from either a response or a `status_update`
+    pub fn capture_available(&self) -> Option<bool> {
+        self.capture_available
+    }
+
+    /// The raw JSON of the last response received, kept so it can be inspected after parsing it
+    /// into a typed response fails
+    pub fn last_response(&self) -> Option<&str> {
+        self.last_response.as_deref()
+    }
+
+    /// Token the camera handed out when authenticating, sent again on reconnect
+    pub fn auth_token(&self) -> Option<&str> {
+        match &self.auth {
+            Some(Method::Auth { auth_token, .. }) => auth_token.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Battery level in percent from the last status, if the firmware reports it
+    pub fn battery_level(&self) -> Option<u8> {
+        self.battery_level
+    }
+
+    pub fn auth(
+        &mut self,
+        device: String,
+        force: String,
+        auth_token: Option<String>,
+    ) -> Result<ResponseStatus> {
+        let res = check_auth(self.send(Method::Auth {
+            device: device.clone(),
+            force: force.clone(),
+            auth_token,
+        }))?;
+        self.update_status(&res);
+        self.auth = Some(Method::Auth {
+            device,
+            force,
+            auth_token: Some(res.auth_token.clone()),
+        });
+        Ok(res)
+    }
+
+    pub fn status(&mut self) -> Result<ResponseStatus> {
+        let res: ResponseStatus = self.send(Method::GetStatus)?;
+        self.update_status(&res);
+        Ok(res)
+    }
+
+    fn update_status(&mut self, res: &ResponseStatus) {
+        self.capture_available = Some(res.capture_available);
+        self.battery_level = res.battery_level;
+    }
+
+    pub fn get_upf_infos(&mut self) -> Result<ResponseGetUpfInfos> {
+        self.send(Method::GetUpfInfos)
+    }
+
+    pub fn delete_upf(&mut self, image_id: String) -> Result<ResponseDelete> {
+        self.send(Method::DeleteUpf { image_id })
+    }
+
+    pub fn get_options(&mut self) -> Result<ResponseGetOptions> {
+        self.send(Method::GetOptions)
+    }
+
+    pub fn get_option_list(&mut self) -> Result<ResponseGetOptionList> {
+        self.send(Method::GetOptionList)
+    }
+
+    pub fn get_option(&mut self, name: KnownOption) -> Result<ResponseGetOption> {
+        self.send(Method::GetOption { name })
+    }
+
+    pub fn set_option(
+        &mut self,
+        name: KnownOption,
+        value: StringOrNumber,
+    ) -> Result<serde_json::Value> {
+        self.send(Method::SetOption { name, value })
+    }
+
+    /// Capture a panorama, optionally overriding some settings for just this capture
+    pub fn capture(&mut self, options: Option<CaptureOptions>) -> Result<ResponseCapture> {
+        let method = match options {
+            Some(options) => Method::CaptureWith { options },
+            None => Method::Capture,
+        };
+        let res: ResponseCapture = self.send(method)?;
+        self.capture_available = Some(res.capture_available);
+        Ok(res)
+    }
+
+    /// Send a request and wait for its response, handling any notifications that arrive first
+    pub fn send<T: Debug + DeserializeOwned>(&mut self, method: Method) -> Result<T> {
+        // an adjacently tagged enum always serializes to an object with a string `method`
+        let value = serde_json::to_value(&method)?;
+        self.call(value["method"].as_str().unwrap(), value.get("params"))
+    }
+
+    /// Call a method by name with arbitrary params and return the raw result, for exploring
+    /// methods `Method` doesn't cover yet
+    pub fn send_raw(&mut self, method: &str, params: Option<&RawValue>) -> Result<Box<RawValue>> {
+        self.call(method, params)
+    }
+
+    /// Call a method by name, serializing `params` as its params and parsing the result into `R`.
+    /// Everything else is built on this, and it lets firmware-specific methods have types of their
+    /// own without adding them to `Method`. Pass `()` or `None` for methods without params.
+    pub fn call<P: Serialize, R: Debug + DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: P,
+    ) -> Result<R> {
+        let params = serde_json::value::to_raw_value(&params)?;
+        let params = (params.get() != "null").then_some(&*params);
+        self.send_retrying(&RawMethod { method, params })
+    }
+
+    fn send_retrying<T: Debug + DeserializeOwned, M: Serialize>(
+        &mut self,
+        method: &M,
+    ) -> Result<T> {
+        let lost = |e: anyhow::Error| {
+            if is_disconnect(&e) {
+                e.context(Disconnected)
+            } else {
+                e
+            }
+        };
+        match self.request(method) {
+            Err(e) if self.reconnect && is_disconnect(&e) => {
+                warn!("{e:#}, reconnecting...");
+                self.reconnect().context(Disconnected)?;
+                self.request(method).map_err(lost)
+            }
+            res => res.map_err(lost),
+        }
+    }
+
+    fn request<T: Debug + DeserializeOwned, M: Serialize>(&mut self, method: &M) -> Result<T> {
+        let start = Instant::now();
+        let id = self.start_request(method)?;
+        let res = self.wait_response(id);
+        debug!("request {id} took {:?}", start.elapsed());
+        // give up on the id either way, a late response to it is ignored
+        self.in_flight.remove(&id);
+        let result = res?;
+        match serde_json::from_str::<T>(result.get()) {
+            Ok(value) => Ok(value),
+            Err(_) if result.get() == "null" => {
+                bail!("The camera answered request {id} without a result")
+            }
+            Err(e) => Err(e).with_context(|| format!("Error parsing response {}", result.get())),
+        }
+    }
+
+    /// Send a request and register it as awaiting a response, returning its id
+    fn start_request<M: Serialize>(&mut self, method: &M) -> Result<u32> {
+        let id = next_id(self.req_id, &self.in_flight);
+        self.req_id = id;
+        let text = serde_json::to_string(&Request {
+            id,
+            method,
+            jsonrpc: JSONRPC_VERSION,
+        })?;
+        debug!("-> {text}");
+        self.trace_frame('>', &text);
+        self.in_flight.insert(id, None);
+        self.ws.send_message(&Message::text(text))?;
+        Ok(id)
+    }
+
+    /// Read packets until the response to request `id` has arrived, routing anything else that
+    /// comes in along the way
+    fn wait_response(&mut self, id: u32) -> Result<Box<RawValue>> {
+        // The stream's read timeout bounds each individual read. Track an overall deadline as well
+        // so a steady stream of unrelated notifications can't keep us waiting forever.
+        let deadline = self
+            .ws
+            .tcp()
+            .read_timeout()?
+            .map(|timeout| Instant::now() + timeout);
+
+        loop {
+            if let Some(res) = self.in_flight.get_mut(&id).and_then(Option::take) {
+                return res.map_err(Into::into);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                bail!("Timed out waiting for response to request {id}");
+            }
+            let text = match self.recv() {
+                Err(e) if is_timeout(&e) => {
+                    bail!("Timed out waiting for response to request {id}")
+                }
+                other => other?,
+            };
+            self.handle_packets(&text)?;
+        }
+    }
+
+    /// Route each packet in a text frame: responses to the request they answer and notifications
+    /// to `handle_notification`
+    fn handle_packets(&mut self, text: &str) -> Result<()> {
+        for text in self.packets.push(text) {
+            let packet = parse_packet(&text)?;
+            // still handled as usual, but a different version likely means fields have changed too
+            if packet.jsonrpc() != JSONRPC_VERSION {
+                warn!(
+                    "camera sent JSON-RPC version {:?}, expected {JSONRPC_VERSION:?}: {text}",
+                    packet.jsonrpc()
+                );
+            }
+            let (id, res) = match packet {
+                PacketIncoming::Response(r) => {
+                    if let Some(warning) = &r.warning {
+                        warn!("request {}: {warning}", r.id);
+                    }
+                    let result = match r.result {
+                        Some(result) => result.to_owned(),
+                        None => RawValue::from_string("null".into())?,
+                    };
+                    (r.id, Ok(result))
+                }
+                PacketIncoming::Error(e) => (e.id, Err(e.error)),
+                PacketIncoming::IncomingRequest(req) => {
+                    self.handle_notification(req)?;
+                    continue;
+                }
+            };
+            match self.in_flight.get_mut(&id) {
+                Some(slot) => {
+                    *slot = Some(res);
+                    self.last_response = Some(text);
+                }
+                None => debug!("ignoring response to request {id} that is no longer awaited"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle any frames the camera sent while no request was in flight, without blocking. This
+    /// answers keepalive pings and applies notifications.
+    pub fn poll(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        match self.poll_pending() {
+            Err(e) if self.reconnect && is_disconnect(&e) => {
+                warn!("{e:#}, reconnecting...");
+                self.reconnect()
+            }
+            res => res,
+        }
+    }
+
+    /// Handle every frame that is buffered or arriving, without waiting for new ones
+    fn poll_pending(&mut self) -> Result<()> {
+        while self.ws.has_pending()? {
+            if let Some(text) = self.recv_frame()? {
+                self.handle_packets(&text)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a notification pushed by the camera
+    fn handle_notification(&mut self, req: IncomingRequest) -> Result<()> {
+        match req.method.as_str() {
+            "status_update" => {
+                let update: StatusUpdate = serde_json::from_str(req.params.get())
+                    .with_context(|| format!("Error parsing status_update {}", req.params))?;
+                if let Some(available) = update.capture_available {
+                    if self.capture_available != Some(available) {
+                        info!(
+                            "capture {}",
+                            if available {
+                                "available"
+                            } else {
+                                "unavailable"
+                            }
+                        );
+                    }
+                    self.capture_available = Some(available);
+                }
+            }
+            _ => warn!("unexpected packet {:#?}", req),
+        }
+        Ok(())
+    }
+
+    /// Receive the next text frame, answering pings and skipping any other frames along the way
+    fn recv(&mut self) -> Result<String> {
+        loop {
+            if let Some(text) = self.recv_frame()? {
+                return Ok(text);
+            }
+        }
+    }
+
+    /// Receive a single frame, returning its contents if it was a text frame
+    fn recv_frame(&mut self) -> Result<Option<String>> {
+        match self.ws.recv_message()? {
+            OwnedMessage::Text(text) => {
+                debug!("<- {text}");
+                self.trace_frame('<', &text);
+                return Ok(Some(text));
+            }
+            OwnedMessage::Close(_) => {
+                info!("camera closed the connection");
+                return Err(ConnectionClosed.into());
+            }
+            OwnedMessage::Binary(data) => {
+                warn!("skipping unexpected binary frame ({} bytes)", data.len());
+            }
+            OwnedMessage::Ping(data) => self.ws.send_message(&OwnedMessage::Pong(data))?,
+            OwnedMessage::Pong(_) => {}
+        }
+        Ok(None)
+    }
+
+    fn trace_frame(&mut self, direction: char, text: &str) {
+        let Some(trace) = &mut self.trace else {
+            return;
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        // the trace is a debugging aid, so failing to write it shouldn't fail the request
+        if let Err(e) = writeln!(trace, "{time:.3} {direction} {text}").and_then(|_| trace.flush())
+        {
+            warn!("failed to write protocol trace, disabling it: {e}");
+            self.trace = None;
+        }
+    }
+}
+
+/// The id to use after `last`, wrapping around on overflow and skipping any still awaiting a
+/// response. 0 is skipped too, so ids look the same as on a fresh connection after wrapping.
+fn next_id<V>(last: u32, in_flight: &HashMap<u32, V>) -> u32 {
+    let mut id = last;
+    loop {
+        id = id.wrapping_add(1);
+        if id != 0 && !in_flight.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
+/// Whether an error means the connection is gone and a new one is needed
+fn is_disconnect(e: &anyhow::Error) -> bool {
+    if e.is::<ConnectionClosed>() {
+        return true;
+    }
+    let io = match e.downcast_ref::<WebSocketError>() {
+        Some(WebSocketError::NoDataAvailable) => return true,
+        Some(WebSocketError::IoError(e)) => e,
+        _ => match e.downcast_ref::<std::io::Error>() {
+            Some(e) => e,
+            None => return false,
+        },
+    };
+    matches!(
+        io.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Whether connecting failed because nothing answered at the address, as opposed to e.g. a TLS or
+/// handshake problem
+fn is_unreachable(e: &WebSocketError) -> bool {
+    matches!(
+        e,
+        WebSocketError::IoError(e) if matches!(
+            e.kind(),
+            std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::HostUnreachable
+                | std::io::ErrorKind::NetworkUnreachable
+        )
+    )
+}
+
+fn is_timeout(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<WebSocketError>(),
+        Some(WebSocketError::IoError(e))
+            if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_ids() {
+        let mut in_flight = HashMap::new();
+        assert_eq!(next_id(0, &in_flight), 1);
+        assert_eq!(next_id(u32::MAX, &in_flight), 1);
+        in_flight.insert(1, ());
+        in_flight.insert(2, ());
+        assert_eq!(next_id(u32::MAX, &in_flight), 3);
+        assert_eq!(next_id(1, &in_flight), 3);
+    }
+
+    #[test]
+    fn address() {
+        assert!(parse_address("ws://192.168.80.80:12345/8086").is_ok());
+        assert!(parse_address("wss://camera.local:12345/8086").is_ok());
+        assert!(matches!(
+            parse_address("ws://[fe80::1]:12345/8086").unwrap().host(),
+            Some(websocket::url::Host::Ipv6(_))
+        ));
+        assert!(matches!(
+            parse_address("ws://panono.local:12345/8086")
+                .unwrap()
+                .host(),
+            Some(websocket::url::Host::Domain("panono.local"))
+        ));
+        for bad in [
+            "192.168.80.80:12345/8086",
+            "http://192.168.80.80:12345/8086",
+            "ws://192.168.80.80/8086",
+            "ws://",
+        ] {
+            let e = parse_address(bad).unwrap_err().to_string();
+            assert!(e.contains(ADDRESS_EXAMPLE), "{bad}: {e}");
+        }
+    }
+
+    #[test]
+    fn connect_ipv6() {
+        let Ok(mut server) = websocket::sync::Server::bind("[::1]:0") else {
+            eprintln!("IPv6 loopback unavailable, skipping");
+            return;
+        };
+        let port = server.local_addr().unwrap().port();
+        let camera = std::thread::spawn(move || {
+            server
+                .accept()
+                .ok()
+                .unwrap()
+                .use_protocol("rust-websocket")
+                .accept()
+                .ok()
+                .unwrap()
+        });
+        let client = PanonoClient::connect(&format!("ws://[::1]:{port}/8086"), false).unwrap();
+        assert_eq!(client.web_ui_url().unwrap(), "http://[::1]/");
+        camera.join().unwrap();
+    }
+
+    #[test]
+    fn disconnected() {
+        for reconnect in [false, true] {
+            let mut server = websocket::sync::Server::bind("127.0.0.1:0").unwrap();
+            let port = server.local_addr().unwrap().port();
+            // accept the connection and hang up straight away, and stop listening for a reconnect
+            let camera = std::thread::spawn(move || {
+                let client = server.accept().ok().unwrap();
+                drop(client.use_protocol("rust-websocket").accept().ok().unwrap());
+            });
+            let mut client =
+                PanonoClient::connect(&format!("ws://127.0.0.1:{port}/8086"), false).unwrap();
+            client.set_reconnect(reconnect);
+            camera.join().unwrap();
+            let e = client.status().unwrap_err();
+            assert!(e.is::<Disconnected>(), "{e:#}");
+        }
+    }
+
+    #[test]
+    fn missing_result() {
+        let mut server = websocket::sync::Server::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        // answer each request without a result, once leaving it out and once as null
+        let camera = std::thread::spawn(move || {
+            let mut client = server
+                .accept()
+                .ok()
+                .unwrap()
+                .use_protocol("rust-websocket")
+                .accept()
+                .ok()
+                .unwrap();
+            for result in ["", r#","result":null"#] {
+                let OwnedMessage::Text(text) = client.recv_message().unwrap() else {
+                    panic!("expected a text frame");
+                };
+                let id = serde_json::from_str::<serde_json::Value>(&text).unwrap()["id"].clone();
+                let res = format!(r#"{{"id":{id},"jsonrpc":"2.0"{result}}}"#);
+                client.send_message(&Message::text(res)).unwrap();
+            }
+        });
+        let mut client =
+            PanonoClient::connect(&format!("ws://127.0.0.1:{port}/8086"), false).unwrap();
+        let e = client.status().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "The camera answered request 1 without a result"
+        );
+        // callers that don't need a result still succeed
+        assert_eq!(client.send_raw("set_option", None).unwrap().get(), "null");
+        camera.join().unwrap();
+    }
+
+    #[test]
+    fn poll_buffered_frames() {
+        let mut server = websocket::sync::Server::bind("127.0.0.1:0").unwrap();
+        let address = format!("ws://{}/8086", server.local_addr().unwrap());
+        let (sent_tx, sent_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut client = server
+                .accept()
+                .ok()
+                .unwrap()
+                .use_protocol("rust-websocket")
+                .accept()
+                .unwrap();
+            // the first frame and part of the second in one write, so the client's reader takes
+            // them off the socket together, then the rest of the second frame after a pause
+            let mut frames = vec![];
+            for available in [false, true] {
+                let text = format!(
+                    r#"{{"jsonrpc":"2.0","method":"status_update","params":{{"capture_available":{available}}}}}"#
+                );
+                frames.extend([0x81, text.len() as u8]);
+                frames.extend(text.as_bytes());
+            }
+            let (first, rest) = frames.split_at(frames.len() - 10);
+            client.writer_mut().write_all(first).unwrap();
+            sent_tx.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            client.writer_mut().write_all(rest).unwrap();
+            // hold the connection open until the test is done
+            let _ = client.recv_message();
+        });
+
+        let mut client = PanonoClient::connect(&address, false).unwrap();
+        sent_rx.recv().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client.capture_available().is_none() {
+            assert!(Instant::now() < deadline, "nothing received");
+            client.poll().unwrap();
+        }
+        // the second frame had started arriving, so the same poll must have waited for the rest
+        assert_eq!(client.capture_available(), Some(true));
+    }
+
+    #[test]
+    fn mock_timeout() {
+        let camera = crate::mock::MockCamera::start().unwrap();
+        camera.reply(
+            "get_status",
+            crate::mock::Reply::Delayed(Duration::from_secs(2), serde_json::json!({})),
+        );
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+        client
+            .set_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let e = client.status().unwrap_err();
+        assert_eq!(e.to_string(), "Timed out waiting for response to request 1");
+    }
+
+    #[test]
+    fn mock_reconnect() {
+        let camera = crate::mock::MockCamera::start().unwrap();
+        camera.reply_once("get_status", crate::mock::Reply::HangUp);
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+        client.set_reconnect(true);
+        client.auth("test".into(), "test".into(), None).unwrap();
+        assert!(client.status().unwrap().capture_available);
+        assert_eq!(camera.connections(), 2);
+        // authenticated again on the new connection with the token from the first
+        let methods = camera
+            .requests()
+            .iter()
+            .map(|r| r["method"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(methods, ["auth", "get_status", "auth", "get_status"]);
+        assert_eq!(
+            camera.requests()[2]["params"]["auth_token"],
+            "b6e5a4c1f0d94c0e"
+        );
+    }
+
+    #[test]
+    fn last_response() {
+        let camera = crate::mock::MockCamera::start().unwrap();
+        camera.reply(
+            "get_status",
+            crate::mock::Reply::Result(serde_json::json!({"firmware_version": 3})),
+        );
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+        assert_eq!(client.last_response(), None);
+        assert!(client.status().is_err());
+        let raw: serde_json::Value = serde_json::from_str(client.last_response().unwrap()).unwrap();
+        assert_eq!(raw["result"]["firmware_version"], 3);
+    }
+
+    #[test]
+    fn mock_error() {
+        let camera = crate::mock::MockCamera::start().unwrap();
+        camera.reply(
+            "delete_upf",
+            crate::mock::Reply::Error {
+                code: 309,
+                message: "delete_failed".into(),
+            },
+        );
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+        let e = client.delete_upf("missing".into()).unwrap_err();
+        assert_eq!(e.downcast_ref::<ResponseError>().unwrap().code, 309);
+        assert!(client.send_raw("get_network_info", None).is_err());
+    }
+
+    #[test]
+    fn call() {
+        #[derive(Serialize)]
+        struct Params {
+            image_id: &'static str,
+        }
+        #[derive(Debug, serde::Deserialize)]
+        struct Deleted {
+            panorama: bool,
+        }
+
+        let camera = crate::mock::MockCamera::start().unwrap();
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+        let res: Deleted = client
+            .call(
+                "delete_upf",
+                Params {
+                    image_id: "4fd70dfc",
+                },
+            )
+            .unwrap();
+        assert!(res.panorama);
+        let _: serde_json::Value = client.call("get_status", ()).unwrap();
+        client.get_upf_infos().unwrap();
+
+        let requests = camera.requests();
+        assert_eq!(
+            requests[0]["params"],
+            serde_json::json!({"image_id": "4fd70dfc"})
+        );
+        for request in &requests[1..] {
+            assert!(request.get("params").is_none(), "{request}");
+        }
+    }
+
+    #[test]
+    fn auth_rejected() {
+        let camera = crate::mock::MockCamera::start().unwrap();
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+
+        let mut rejected = serde_json::to_value(client.status().unwrap()).unwrap();
+        rejected["is_auth"] = false.into();
+        camera.reply_once("auth", crate::mock::Reply::Result(rejected));
+        camera.reply_once(
+            "auth",
+            crate::mock::Reply::Error {
+                code: 401,
+                message: "unauthorized".into(),
+            },
+        );
+        for _ in 0..2 {
+            let e = client.auth("test".into(), "test".into(), None).unwrap_err();
+            assert!(e.downcast_ref::<AuthRejected>().is_some(), "{e:#}");
+        }
+        client.auth("test".into(), "test".into(), None).unwrap();
+    }
+
+    #[test]
+    fn connect_refused() {
+        // grab a free port and release it again so nothing is listening there
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = format!("ws://127.0.0.1:{port}/8086");
+        let e = PanonoClient::connect(&address, false).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            format!("Failed to connect to {address}, check the camera is on and reachable from this network")
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use console::Style;
+use std::{
+    fmt::Display,
+    io::IsTerminal,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Whether errors and warnings are colored, see `init`
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Color errors and warnings unless --no-color was given, `NO_COLOR` is set to anything or stderr,
+/// where both are printed, isn't a terminal
+pub fn init(no_color: bool) {
+    let no_color = no_color || std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    ENABLED.store(
+        !no_color && std::io::stderr().is_terminal(),
+        Ordering::Relaxed,
+    );
+}
+
+fn paint(style: Style, text: impl Display) -> String {
+    style
+        .force_styling(ENABLED.load(Ordering::Relaxed))
+        .apply_to(text)
+        .to_string()
+}
+
+pub fn error(text: impl Display) -> String {
+    paint(Style::new().red(), text)
+}
+
+pub fn warning(text: impl Display) -> String {
+    paint(Style::new().yellow(), text)
+}
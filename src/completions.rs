@@ -0,0 +1,194 @@
+use clap::{Arg, Command, ValueEnum};
+use std::io::{self, Write};
+
+/// Shells completion scripts can be generated for
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Write a script completing the command's flags, and the values of flags with a fixed set of them
+pub fn generate(shell: Shell, mut command: Command, out: &mut impl Write) -> io::Result<()> {
+    // fills in the generated help and version flags and each flag's number of values
+    command.build();
+    let name = command.get_name();
+    let flags = command
+        .get_arguments()
+        .filter(|a| !a.is_positional())
+        .collect::<Vec<_>>();
+    match shell {
+        Shell::Bash => {
+            let words = flags
+                .iter()
+                .flat_map(|a| spellings(a))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let function = format!("_{}", name.replace('-', "_"));
+            writeln!(out, "{function}() {{")?;
+            writeln!(out, "    local cur prev")?;
+            writeln!(out, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"")?;
+            writeln!(out, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"")?;
+            writeln!(out, "    case \"$prev\" in")?;
+            for flag in &flags {
+                if let Some(values) = possible_values(flag) {
+                    writeln!(out, "        {})", spellings(flag).join("|"))?;
+                    writeln!(
+                        out,
+                        "            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;",
+                        values.join(" ")
+                    )?;
+                }
+            }
+            writeln!(out, "    esac")?;
+            writeln!(out, "    if [[ \"$cur\" == -* ]]; then")?;
+            writeln!(
+                out,
+                "        COMPREPLY=($(compgen -W \"{words}\" -- \"$cur\"))"
+            )?;
+            writeln!(out, "    fi")?;
+            writeln!(out, "}}")?;
+            writeln!(out, "complete -o default -F {function} {name}")?;
+        }
+        Shell::Zsh => {
+            writeln!(out, "#compdef {name}")?;
+            writeln!(out, "_arguments \\")?;
+            for flag in &flags {
+                let help = escape(&help(flag), "[]'");
+                let action = match (possible_values(flag), takes_value(flag)) {
+                    (Some(values), _) => format!(":value:({})", values.join(" ")),
+                    (None, true) => ":value:_files".to_string(),
+                    (None, false) => String::new(),
+                };
+                for spelling in spellings(flag) {
+                    writeln!(out, "  '{spelling}[{help}]{action}' \\")?;
+                }
+            }
+            writeln!(out, "  '*::args:_files'")?;
+        }
+        Shell::Fish => {
+            for flag in &flags {
+                write!(out, "complete -c {name}")?;
+                if let Some(short) = flag.get_short() {
+                    write!(out, " -s {short}")?;
+                }
+                if let Some(long) = flag.get_long() {
+                    write!(out, " -l {long}")?;
+                }
+                if let Some(values) = possible_values(flag) {
+                    write!(out, " -x -a '{}'", values.join(" "))?;
+                } else if takes_value(flag) {
+                    write!(out, " -r")?;
+                }
+                writeln!(out, " -d '{}'", escape(&help(flag), "'"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn spellings(arg: &Arg) -> Vec<String> {
+    let short = arg.get_short().map(|s| format!("-{s}"));
+    let long = arg.get_long().map(|l| format!("--{l}"));
+    short.into_iter().chain(long).collect()
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_num_args().is_some_and(|n| n.takes_values())
+}
+
+fn possible_values(arg: &Arg) -> Option<Vec<String>> {
+    let values = arg.get_possible_values();
+    (!values.is_empty()).then(|| values.iter().map(|v| v.get_name().to_string()).collect())
+}
+
+/// First line of the flag's help
+fn help(arg: &Arg) -> String {
+    arg.get_help()
+        .map(|h| h.to_string())
+        .unwrap_or_default()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn escape(text: &str, chars: &str) -> String {
+    text.chars()
+        .flat_map(|c| match c {
+            '\'' if chars.contains(c) => "'\\''".chars().collect::<Vec<_>>(),
+            c if chars.contains(c) => vec!['\\', c],
+            c => vec![c],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Args;
+    use clap::CommandFactory;
+
+    fn script(shell: Shell) -> String {
+        let mut out = vec![];
+        generate(shell, Args::command().name("panonoctl-rs"), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn completions() {
+        let bash = script(Shell::Bash);
+        assert!(bash.contains("--output-dir"));
+        assert!(bash.contains("complete -o default -F _panonoctl_rs panonoctl-rs"));
+        assert!(bash.contains("compgen -W \"bash zsh fish\""));
+        assert!(script(Shell::Zsh).contains("'--json[Print command results as JSON"));
+        let fish = script(Shell::Fish);
+        assert!(fish.contains("complete -c panonoctl-rs -s v -l verbose -d"));
+        assert!(fish.contains("complete -c panonoctl-rs -l output-dir -r"));
+    }
+
+    /// Check each script parses with its shell, for the shells that are installed
+    #[test]
+    fn syntax() {
+        let tricky = Command::new("panonoctl-rs")
+            .arg(
+                Arg::new("quoted")
+                    .long("quoted")
+                    .help(r#"Help with 'quotes', "double quotes", [brackets], $HOME and `ticks`"#),
+            )
+            .arg(
+                Arg::new("mode")
+                    .long("mode")
+                    .value_parser(["a-b", "c_d"])
+                    .help("It's a choice"),
+            );
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let name = format!("{shell:?}").to_lowercase();
+            for command in [Args::command().name("panonoctl-rs"), tricky.clone()] {
+                let mut script = vec![];
+                generate(shell, command, &mut script).unwrap();
+                let child = std::process::Command::new(&name)
+                    .arg("-n")
+                    .stdin(std::process::Stdio::piped())
+                    .stderr(std::process::Stdio::piped())
+                    .spawn();
+                let mut child = match child {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        eprintln!("{name} isn't installed, skipping");
+                        break;
+                    }
+                    child => child.unwrap(),
+                };
+                child.stdin.take().unwrap().write_all(&script).unwrap();
+                let output = child.wait_with_output().unwrap();
+                assert!(
+                    output.status.success(),
+                    "{name}: {}\n{}",
+                    String::from_utf8_lossy(&output.stderr),
+                    String::from_utf8_lossy(&script)
+                );
+            }
+        }
+    }
+}
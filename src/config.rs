@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use clap::Command;
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+
+/// Defaults for command line flags, so settings like the download folder don't need to be passed
+/// every time. Flags given on the command line take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Used when neither an address argument nor PANONO_ADDRESS is given
+    pub address: Option<String>,
+    pub timeout: Option<u64>,
+    pub discovery_timeout: Option<u64>,
+    pub scan_subnet: Option<String>,
+    pub device_name: Option<String>,
+    pub force: Option<String>,
+    pub output_dir: Option<String>,
+    pub name_template: Option<String>,
+    pub jobs: Option<usize>,
+    pub retries: Option<u32>,
+    pub poll_interval: Option<u64>,
+    pub poll_status: Option<u64>,
+    pub capture_timeout: Option<u64>,
+    pub trace_file: Option<String>,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("panonoctl").join("config.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Make the configured values the defaults of the matching flags. The address is left out since
+    /// it is positional, see `main`.
+    pub fn apply(&self, mut command: Command) -> Command {
+        let defaults = [
+            ("timeout", self.timeout.map(|v| v.to_string())),
+            (
+                "discovery_timeout",
+                self.discovery_timeout.map(|v| v.to_string()),
+            ),
+            ("scan_subnet", self.scan_subnet.clone()),
+            ("device_name", self.device_name.clone()),
+            ("force", self.force.clone()),
+            ("output_dir", self.output_dir.clone()),
+            ("name_template", self.name_template.clone()),
+            ("jobs", self.jobs.map(|v| v.to_string())),
+            ("retries", self.retries.map(|v| v.to_string())),
+            ("poll_interval", self.poll_interval.map(|v| v.to_string())),
+            ("poll_status", self.poll_status.map(|v| v.to_string())),
+            (
+                "capture_timeout",
+                self.capture_timeout.map(|v| v.to_string()),
+            ),
+            ("trace_file", self.trace_file.clone()),
+        ];
+        for (id, value) in defaults {
+            if let Some(value) = value {
+                command = command.mut_arg(id, |arg| arg.default_value(value));
+            }
+        }
+        command
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Args;
+    use clap::{CommandFactory, FromArgMatches};
+
+    #[test]
+    fn flags_override_config() {
+        let config: Config =
+            serde_json::from_str(r#"{"jobs": 8, "output_dir": "~/panono", "timeout": 5}"#).unwrap();
+        let parse = |argv: &[&str]| {
+            let matches = config.apply(Args::command()).get_matches_from(argv);
+            Args::from_arg_matches(&matches).unwrap()
+        };
+        let args = parse(&["panonoctl", "--jobs", "2"]);
+        assert_eq!(args.jobs, 2);
+        assert_eq!(args.output_dir, "~/panono");
+        assert_eq!(args.timeout, 5);
+        assert_eq!(args.retries, 3);
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(serde_json::from_str::<Config>(r#"{"job": 8}"#).is_err());
+    }
+}
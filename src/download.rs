@@ -0,0 +1,709 @@
+use anyhow::{bail, Context, Result};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use ring::digest::{Context as Digest, SHA256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+use crate::events::{self, Event};
+
+/// A single file to fetch from the camera
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub image_id: String,
+    pub url: String,
+    /// Expected size if known, checked once the download completes
+    pub size: Option<u64>,
+    pub path: PathBuf,
+    /// Swap the extension of `path` for one matching the image Content-Type the server sends, for
+    /// previews whose format depends on the firmware. `path` is updated once the download completes.
+    pub detect_image_type: bool,
+}
+
+/// Extensions `image_extension` can pick, so existing downloads can be found whichever was used
+pub const IMAGE_EXTENSIONS: [&str; 2] = ["jpg", "png"];
+
+/// File extension for an image Content-Type such as `image/jpeg`, ignoring any parameters
+pub fn image_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    match mime.to_ascii_lowercase().as_str() {
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        _ => None,
+    }
+}
+
+/// Auth token of the camera in use, for firmware that only serves files to the session
+static AUTH_TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// Set on connecting and whenever another camera is put in use
+pub fn set_auth_token(token: Option<String>) {
+    *AUTH_TOKEN.lock().unwrap() = token;
+}
+
+fn auth_token() -> Option<String> {
+    AUTH_TOKEN.lock().unwrap().clone()
+}
+
+/// HTTP client for downloads, shared so connections to the camera are reused
+fn agent() -> &'static ureq::Agent {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    AGENT.get_or_init(ureq::Agent::new)
+}
+
+/// Like `agent`, but leaves redirects to `get_with_token` so the token isn't sent on to other
+/// hosts
+fn token_agent() -> &'static ureq::Agent {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    AGENT.get_or_init(|| ureq::AgentBuilder::new().redirects(0).build())
+}
+
+/// Redirects `get_with_token` follows itself, the same as ureq's default
+const MAX_REDIRECTS: u32 = 5;
+
+fn host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(str::to_string)
+}
+
+/// Note when a download was redirected, calling out other hosts since the camera may be pointing
+/// at cloud storage or going through a proxy
+fn log_redirect(requested: &str, url: &str) {
+    if url == requested {
+        return;
+    }
+    if host(url) == host(requested) {
+        log::info!("{requested} redirected to {url}");
+    } else {
+        log::warn!("{requested} redirected to another host: {url}");
+    }
+}
+
+/// GET `url`, asking for the rest of a partial file from `range_from`. Some firmware refuses to
+/// serve files without the session's auth token, so a 401 or 403 is retried once with
+/// `auth_token` added as a query parameter.
+// kept as ureq's own error so callers can match on the status
+#[allow(clippy::result_large_err)]
+fn get(
+    url: &str,
+    auth_token: Option<&str>,
+    range_from: u64,
+) -> Result<ureq::Response, ureq::Error> {
+    let res =
+        request(agent(), url, None, range_from).inspect(|res| log_redirect(url, res.get_url()));
+    match (res, auth_token) {
+        (Err(ureq::Error::Status(401 | 403, _)), Some(token)) => {
+            log::info!("{url} was refused, retrying with the auth token");
+            get_with_token(url, token, range_from)
+        }
+        (res, _) => res,
+    }
+}
+
+/// GET `url` with the auth token, following redirects by hand. The token only goes to the
+/// camera's host, a redirect elsewhere is followed without it.
+#[allow(clippy::result_large_err)]
+fn get_with_token(url: &str, token: &str, range_from: u64) -> Result<ureq::Response, ureq::Error> {
+    let mut current = url.to_string();
+    for _ in 0..MAX_REDIRECTS {
+        let res = request(token_agent(), &current, Some(token), range_from)?;
+        let Some(next) = (300..400)
+            .contains(&res.status())
+            .then(|| res.header("Location"))
+            .flatten()
+            .and_then(|location| Url::parse(&current).ok()?.join(location).ok())
+        else {
+            return Ok(res);
+        };
+        log_redirect(&current, next.as_str());
+        if host(next.as_str()) != host(url) {
+            return request(agent(), next.as_str(), None, range_from);
+        }
+        current = next.into();
+    }
+    // still redirecting, so leave the rest to ureq and its own limit, without the token
+    request(agent(), &current, None, range_from)
+}
+
+#[allow(clippy::result_large_err)]
+fn request(
+    agent: &ureq::Agent,
+    url: &str,
+    auth_token: Option<&str>,
+    range_from: u64,
+) -> Result<ureq::Response, ureq::Error> {
+    let mut req = agent.get(url);
+    if let Some(token) = auth_token {
+        req = req.query("auth_token", token);
+    }
+    if range_from > 0 {
+        req = req.set("Range", &format!("bytes={range_from}-"));
+    }
+    req.call()
+}
+
+/// Explain a download the camera refused, which retrying won't fix
+fn refused(e: ureq::Error, auth_token: Option<&str>) -> anyhow::Error {
+    let explanation = match (&e, auth_token) {
+        (ureq::Error::Status(401 | 403, _), Some(_)) => {
+            "The camera refused the download even with the auth token, reconnect for a fresh session"
+        }
+        (ureq::Error::Status(401 | 403, _), None) => {
+            "The camera refused the download and there is no auth token to send"
+        }
+        _ => return e.into(),
+    };
+    anyhow::Error::from(e).context(explanation)
+}
+
+/// Set by --quiet to keep progress bars off the terminal
+static HIDE_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Stop drawing progress bars and spinners, for scripts that only want the command's output
+pub fn hide_progress() {
+    HIDE_PROGRESS.store(true, Ordering::Relaxed);
+}
+
+fn progress_bar(len: u64) -> ProgressBar {
+    if HIDE_PROGRESS.load(Ordering::Relaxed) {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(len)
+    }
+}
+
+/// Spinner with an elapsed timer for waiting on the camera
+pub fn spinner(message: &'static str) -> ProgressBar {
+    let spinner = progress_bar(0)
+        .with_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed}]").unwrap())
+        .with_message(message);
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
+/// Token bucket shared by all workers so `rate` caps the combined throughput rather than each
+/// download's
+pub struct RateLimit {
+    /// Bytes per second
+    rate: u64,
+    /// Bytes that may be read right away and when that was last topped up. Goes negative when a read
+    /// overdraws it, which the reader then sleeps off.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Account for `bytes` just read, sleeping long enough to bring the average back down to the
+    /// rate
+    fn consume(&self, bytes: usize) {
+        let rate = self.rate as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            // allow bursts of up to a second's worth after sitting idle
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *tokens -= bytes as f64;
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / rate))
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Reader that sleeps between chunks to stay under a `RateLimit`
+struct Throttled<'a, R> {
+    inner: R,
+    limit: Option<&'a RateLimit>,
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(limit) = self.limit {
+            limit.consume(n);
+        }
+        Ok(n)
+    }
+}
+
+/// Reader that reports how far a download has got as `download_progress` events, at most once a
+/// second
+struct ProgressEvents<'a, R> {
+    inner: R,
+    image_id: &'a str,
+    position: u64,
+    size: Option<u64>,
+    last: Instant,
+}
+
+impl<R: Read> Read for ProgressEvents<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        if self.last.elapsed() >= Duration::from_secs(1) {
+            self.last = Instant::now();
+            events::emit(&Event::DownloadProgress {
+                image_id: self.image_id,
+                bytes: self.position,
+                size: self.size,
+            });
+        }
+        Ok(n)
+    }
+}
+
+/// Writer that feeds everything written through it into a SHA-256 digest
+struct Hashing<W> {
+    inner: W,
+    digest: Digest,
+}
+
+impl<W: Write> Hashing<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            digest: Digest::new(&SHA256),
+        }
+    }
+
+    /// Feed data that is already in place into the digest without writing it again
+    fn skip(&mut self, mut reader: impl Read) -> io::Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(()),
+                n => self.digest.update(&buf[..n]),
+            }
+        }
+    }
+
+    /// Hex encoded digest of everything written so far
+    fn finish(self) -> String {
+        hex(self.digest.finish().as_ref())
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn progress_style() -> ProgressStyle {
+    // indicatif's rate and eta are smoothed over recent progress, so they hold steady as long as
+    // progress is reported in reasonably sized chunks
+    ProgressStyle::with_template(
+        "{prefix} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} {binary_bytes_per_sec} ({eta}) {msg}",
+    )
+    .unwrap()
+    .progress_chars("#>-")
+}
+
+/// Download all files using up to `jobs` concurrent workers, retrying transient failures up to
+/// `retries` times. A failed download does not stop the others, the result of each is returned in
+/// completion order along with the SHA-256 of the file. `max_rate` caps the combined download speed
+/// in bytes per second.
+pub fn download_all(
+    downloads: Vec<Download>,
+    jobs: usize,
+    retries: u32,
+    max_rate: Option<u64>,
+) -> Vec<(Download, Result<String>)> {
+    let total = downloads.len();
+    let limit = max_rate.map(RateLimit::new);
+    let mp = MultiProgress::new();
+    if HIDE_PROGRESS.load(Ordering::Relaxed) {
+        mp.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let queue = Mutex::new(downloads.into_iter().enumerate());
+    let results = Mutex::new(Vec::with_capacity(total));
+
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, total.max(1)) {
+            s.spawn(|| loop {
+                let Some((i, mut download)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let pb = mp.add(
+                    progress_bar(download.size.unwrap_or(0))
+                        .with_style(progress_style())
+                        .with_prefix(format!("[{}/{}] {}", i + 1, total, download.image_id)),
+                );
+                events::emit(&Event::DownloadStarted {
+                    image_id: &download.image_id,
+                    path: &download.path,
+                    size: download.size,
+                });
+                let res = fetch_with_retries(&mut download, &pb, retries, limit.as_ref());
+                match &res {
+                    Ok(sha256) => {
+                        pb.finish();
+                        events::emit(&Event::DownloadComplete {
+                            image_id: &download.image_id,
+                            path: &download.path,
+                            sha256,
+                        });
+                    }
+                    Err(e) => {
+                        pb.abandon_with_message(format!("failed: {e:#}"));
+                        events::emit(&Event::Error {
+                            image_id: Some(&download.image_id),
+                            message: format!("{e:#}"),
+                        });
+                    }
+                }
+                results.lock().unwrap().push((download, res));
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+/// Stream a file straight into `out`, e.g. stdout for piping, with the progress bar drawn on
+/// stderr. There is no partial file to pick up from, so failures are not retried.
+pub fn download_to_writer(url: &str, size: Option<u64>, out: &mut impl Write) -> Result<()> {
+    let token = auth_token();
+    let res = get(url, token.as_deref(), 0).map_err(|e| refused(e, token.as_deref()))?;
+    let length = content_length(&res).or(size).unwrap_or(0);
+    let pb = progress_bar(length).with_style(progress_style());
+    let mut writer = BufWriter::with_capacity(64 * 1024, out);
+    let written = io::copy(&mut pb.wrap_read(res.into_reader()), &mut writer)?;
+    writer.flush()?;
+    if let Some(size) = size.filter(|size| *size != written) {
+        pb.abandon_with_message("failed: size mismatch");
+        bail!("size mismatch: expected {size} bytes but got {written}");
+    }
+    pb.finish();
+    Ok(())
+}
+
+/// Length of the body as it will be read. ureq asks for gzip and decompresses it transparently, in
+/// which case Content-Length is the compressed size and doesn't apply.
+fn content_length(res: &ureq::Response) -> Option<u64> {
+    if res.header("Content-Encoding").is_some() {
+        return None;
+    }
+    res.header("Content-Length").and_then(|l| l.parse().ok())
+}
+
+/// Retry with exponential backoff, picking up from the partial file each time
+fn fetch_with_retries(
+    download: &mut Download,
+    pb: &ProgressBar,
+    retries: u32,
+    limit: Option<&RateLimit>,
+) -> Result<String> {
+    let mut attempt = 0;
+    loop {
+        match fetch(download, pb, limit) {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = Duration::from_secs(1 << attempt);
+                attempt += 1;
+                pb.println(format!(
+                    "{}: {e:#}, retrying in {}s ({attempt}/{retries})",
+                    download.image_id,
+                    delay.as_secs()
+                ));
+                thread::sleep(delay);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Whether an error is likely to go away on retry, i.e. a connection problem or timeout rather
+/// than the server rejecting the request or the file failing to save
+fn is_transient(e: &anyhow::Error) -> bool {
+    if let Some(e) = e.downcast_ref::<ureq::Error>() {
+        return match e {
+            ureq::Error::Status(code, _) => *code >= 500,
+            ureq::Error::Transport(t) => matches!(
+                t.kind(),
+                ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+            ),
+        };
+    }
+    // reading the body fails with these, while writing the file fails with e.g. StorageFull or
+    // PermissionDenied, which retrying won't fix
+    e.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::UnexpectedEof
+        )
+    })
+}
+
+/// Append `suffix` to the file name, e.g. `a.upf` -> `a.upf.part`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Download to the `.part` file and move it into place, returning the SHA-256 of the contents
+fn fetch(download: &mut Download, pb: &ProgressBar, limit: Option<&RateLimit>) -> Result<String> {
+    // data is written here until the download completes and kept around on failure for resuming
+    let part = with_suffix(&download.path, ".part");
+    let offset = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+
+    let token = auth_token();
+    let res = match get(&download.url, token.as_deref(), offset) {
+        // the partial file already holds everything the server has
+        Err(ureq::Error::Status(416, _)) if offset > 0 => {
+            let mut hashing = Hashing::new(io::sink());
+            hashing.skip(File::open(&part)?)?;
+            finish(download, &part, &download.path)?;
+            return Ok(hashing.finish());
+        }
+        res => res.map_err(|e| refused(e, token.as_deref()))?,
+    };
+
+    // the .part file keeps the planned name so retries resume it, only the final name changes
+    let dest = match download
+        .detect_image_type
+        .then(|| image_extension(res.content_type()))
+        .flatten()
+    {
+        Some(extension) => download.path.with_extension(extension),
+        None => download.path.clone(),
+    };
+
+    // servers that ignore the Range header send the whole file back, so start over
+    let resumed = res.status() == 206;
+    let offset = if resumed { offset } else { 0 };
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(&part)
+        .with_context(|| format!("Failed to open {}", part.display()))?;
+
+    let size = content_length(&res)
+        .map(|l| l + offset)
+        .or(download.size)
+        .unwrap_or(0);
+    pb.set_length(size);
+    pb.set_position(offset);
+    if resumed {
+        pb.set_message(format!("resumed at {}", HumanBytes(offset)));
+    }
+
+    // the hash covers the whole file, so catch up on what an earlier attempt already saved
+    let mut hashing = Hashing::new(file);
+    if resumed {
+        hashing.skip(File::open(&part)?)?;
+    }
+    // io::copy reads straight into the BufWriter's buffer, so this is also the read chunk size
+    let mut writer = BufWriter::with_capacity(64 * 1024, &mut hashing);
+    let reader = ProgressEvents {
+        inner: Throttled {
+            inner: res.into_reader(),
+            limit,
+        },
+        image_id: &download.image_id,
+        position: offset,
+        size: (size > 0).then_some(size),
+        last: Instant::now(),
+    };
+    io::copy(&mut pb.wrap_read(reader), &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    finish(download, &part, &dest)?;
+    download.path = dest;
+    Ok(hashing.finish())
+}
+
+/// Move a completed `.part` file to `dest`, or aside as `.corrupt` if its size doesn't match what
+/// the camera reported so the next run downloads it again
+fn finish(download: &Download, part: &Path, dest: &Path) -> Result<()> {
+    let written = fs::metadata(part)?.len();
+    if let Some(size) = download.size.filter(|size| *size != written) {
+        let corrupt = with_suffix(dest, ".corrupt");
+        fs::rename(part, &corrupt)?;
+        bail!(
+            "size mismatch: expected {} bytes but got {}, saved as {}",
+            size,
+            written,
+            corrupt.display()
+        );
+    }
+    fs::rename(part, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_extensions() {
+        assert_eq!(image_extension("image/jpeg"), Some("jpg"));
+        assert_eq!(image_extension("image/PNG; charset=binary"), Some("png"));
+        assert_eq!(image_extension("application/octet-stream"), None);
+        for extension in IMAGE_EXTENSIONS {
+            assert!(["image/jpeg", "image/png"]
+                .iter()
+                .any(|t| image_extension(t) == Some(extension)));
+        }
+    }
+
+    #[test]
+    fn compressed_length() {
+        let res = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"
+            .parse::<ureq::Response>()
+            .unwrap();
+        assert_eq!(content_length(&res), Some(5));
+        let res = "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: 25\r\n\r\n"
+            .parse::<ureq::Response>()
+            .unwrap();
+        assert_eq!(content_length(&res), None);
+    }
+
+    /// Serve HTTP on a local port, answering each request with what `respond` returns for its
+    /// request line and headers. Returns the server's base URL.
+    fn serve(respond: impl Fn(&str, u16) -> String + Send + 'static) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let response = respond(&String::from_utf8_lossy(&request), port);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://127.0.0.1:{port}")
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello";
+
+    fn redirect(location: String) -> String {
+        format!("HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    }
+
+    #[test]
+    fn auth_token_retry() {
+        let url = serve(|request, _| {
+            if request.contains("auth_token=secret") {
+                OK.into()
+            } else {
+                "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+            }
+        }) + "/upfs/a.upf";
+
+        let res = get(&url, Some("secret"), 0).unwrap();
+        assert_eq!(res.into_string().unwrap(), "hello");
+        let e = get(&url, None, 0).unwrap_err();
+        assert!(matches!(e, ureq::Error::Status(403, _)));
+        let e = get(&url, Some("stale"), 0).unwrap_err();
+        assert!(format!("{:#}", refused(e, Some("stale"))).contains("even with the auth token"));
+    }
+
+    #[test]
+    fn redirects() {
+        let base = serve(|request, port| {
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            match path {
+                "/upfs/a.upf" => redirect(format!("http://localhost:{port}/storage/a.upf")),
+                "/loop" => redirect("/loop".into()),
+                _ => OK.into(),
+            }
+        });
+
+        let res = get(&format!("{base}/upfs/a.upf"), None, 0).unwrap();
+        assert!(res.get_url().ends_with("/storage/a.upf"));
+        assert_eq!(res.into_string().unwrap(), "hello");
+
+        let e = anyhow::Error::from(get(&format!("{base}/loop"), None, 0).unwrap_err());
+        assert!(!is_transient(&e));
+    }
+
+    #[test]
+    fn auth_token_redirects() {
+        const FORBIDDEN: &str =
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        // same port, but localhost counts as another host than 127.0.0.1
+        let base = serve(|request, port| {
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            let other_host = request.contains("Host: localhost");
+            match path {
+                "/upfs/a.upf?auth_token=secret" if !other_host => redirect("/storage/a.upf".into()),
+                "/storage/a.upf?auth_token=secret" if !other_host => {
+                    redirect(format!("http://localhost:{port}/cloud/a.upf"))
+                }
+                "/cloud/a.upf" if other_host => OK.into(),
+                _ => FORBIDDEN.into(),
+            }
+        });
+
+        let res = get(&format!("{base}/upfs/a.upf"), Some("secret"), 0).unwrap();
+        assert!(res.get_url().ends_with("/cloud/a.upf"));
+        assert_eq!(res.into_string().unwrap(), "hello");
+    }
+
+    #[test]
+    fn transient() {
+        let io = |kind| anyhow::Error::from(io::Error::from(kind)).context("download failed");
+        assert!(is_transient(&io(io::ErrorKind::ConnectionReset)));
+        assert!(is_transient(&io(io::ErrorKind::TimedOut)));
+        assert!(!is_transient(&io(io::ErrorKind::PermissionDenied)));
+        assert!(!is_transient(&io(io::ErrorKind::NotFound)));
+
+        let get = |url| anyhow::Error::from(get(url, None, 0).unwrap_err());
+        assert!(!is_transient(&get("not a url")));
+        assert!(!is_transient(&get("ftp://127.0.0.1/a.upf")));
+    }
+
+    #[test]
+    fn sha256() {
+        let mut hashing = Hashing::new(vec![]);
+        hashing.skip(&b"ab"[..]).unwrap();
+        hashing.write_all(b"c").unwrap();
+        assert_eq!(hashing.inner, b"c");
+        assert_eq!(
+            hashing.finish(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use serde::Serialize;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set by --events to report the progress of long-running commands as JSON lines
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Something that happened during watch, download or capture_and_download, printed as one JSON
+/// object per line with its kind in the `event` field
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    UpfDiscovered {
+        image_id: &'a str,
+        capture_date: String,
+        size: u64,
+    },
+    DownloadStarted {
+        image_id: &'a str,
+        path: &'a Path,
+        size: Option<u64>,
+    },
+    DownloadProgress {
+        image_id: &'a str,
+        bytes: u64,
+        size: Option<u64>,
+    },
+    DownloadComplete {
+        image_id: &'a str,
+        path: &'a Path,
+        sha256: &'a str,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image_id: Option<&'a str>,
+        message: String,
+    },
+    /// The result a command would otherwise print
+    Result { result: serde_json::Value },
+}
+
+/// Print `event` to stdout if --events was given
+pub fn emit(event: &Event) {
+    if enabled() {
+        // every field serializes infallibly
+        println!("{}", serde_json::to_string(event).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let event = Event::DownloadStarted {
+            image_id: "4fd70dfc",
+            path: Path::new("upfs/4fd70dfc.upf"),
+            size: Some(117964800),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"download_started","image_id":"4fd70dfc","path":"upfs/4fd70dfc.upf","size":117964800}"#
+        );
+        let event = Event::Error {
+            image_id: None,
+            message: "lost the connection".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"error","message":"lost the connection"}"#
+        );
+    }
+}
@@ -0,0 +1,9 @@
+//! Client for the JSON-RPC protocol the Panono camera speaks over its websocket
+
+mod client;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod protocol;
+
+pub use client::{AuthRejected, ConnectionClosed, Disconnected, PanonoClient};
+pub use protocol::*;
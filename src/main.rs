@@ -1,528 +1,2715 @@
+mod cameras;
+mod color;
+mod completions;
+mod config;
+mod download;
+mod events;
+mod manifest;
+mod repl;
+mod scan;
+mod template;
+mod token;
+
 use anyhow::{bail, Context, Result};
-use clap::Parser;
-use indicatif::{ProgressBar, ProgressIterator, ProgressState, ProgressStyle};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::value::RawValue;
+use cameras::{spawn_status_poller, Cameras, Connector};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use config::Config;
+use download::{download_all, download_to_writer, Download, IMAGE_EXTENSIONS};
+use events::Event;
+use indicatif::HumanBytes;
+use manifest::{Manifest, ManifestEntry};
+use panonoctl_rs::{
+    format_timestamp, parse_number, CaptureOptions, Disconnected, KnownOption, PanonoClient,
+    ResponseCapture, ResponseDelete, ResponseError, ResponseGetUpfInfos, StringOrNumber, UpfInfo,
+};
+use repl::Repl;
+use scan::Subnet;
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
-    collections::HashMap,
-    fmt::{Debug, Write},
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
     fs,
-    io::Read,
-    net::TcpStream,
-    path::Path,
+    io::Write,
+    path::{Path, PathBuf},
     rc::Rc,
-};
-use websocket::{sync::Client, ClientBuilder, Message, OwnedMessage};
-
-// TODO
-// {"jsonrpc":"2.0","method":"status_update","params":{"capture_available":false}}
-//
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case", tag = "method", content = "params")]
-enum Method {
-    Auth { device: String, force: String },
-    DeleteUpf { image_id: String },
-    GetUpfInfos,
-    GetStatus,
-    GetOptions,
-    GetOptionList,
-    GetOption { name: String },
-    Capture,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Request {
-    id: u32,
-    #[serde(flatten)]
-    method: Method,
-    jsonrpc: &'static str,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct IncomingRequest<'r> {
-    jsonrpc: String,
-    method: String,
-    #[serde(borrow)]
-    params: &'r RawValue,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Response<'r> {
-    id: u32,
-    jsonrpc: String,
-    #[serde(borrow)]
-    result: &'r RawValue,
-    warning: Option<ResponseWarning>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ResponseWarning {
-    code: u32,
-    message: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ResponseStatus {
-    auth_token: String,
-    capture_available: bool,
-    current_time: String,
-    device_id: String,
-    firmware_update_url: String,
-    firmware_version: String,
-    is_auth: bool,
-    serial_number: String,
-    storage: HashMap<String, Storage>,
-    update_ready: bool,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ResponseGetUpfInfos {
-    is_full: bool,
-    upf_infos: Vec<UpfInfo>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ResponseGetOptionList {
-    options: Vec<CameraOption>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
-enum CameraOption {
-    Boolean {
-        name: String,
-        constraints: Vec<Constraint<bool>>,
-    },
-    Enumeration {
-        name: String,
-        constraints: Vec<Constraint<String>>,
-    },
-    Number {
-        name: String,
-        constraints: Vec<Constraint<String>>,
-    },
-    Integer {
-        name: String,
-        constraints: Vec<Constraint<u64>>,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
     },
+    thread,
+    time::{Duration, Instant},
+};
+use template::NameTemplate;
+use token::TokenStore;
+
+/// Set by --quiet to leave out status messages that aren't the result of a command
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Print a status message to stderr unless --quiet was given, keeping stdout for command output
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "constraint", rename_all = "snake_case")]
-enum Constraint<T> {
-    Values { value: Vec<T> },
-    Min { value: T },
-    Max { value: T },
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ResponseGetOption {
-    name: String,
-    value: StringOrNumber,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum StringOrNumber {
-    String(String),
-    Number(f64),
-    Bool(bool),
-}
-
-// TODO error reponse {"error":{"code":309,"details":{"panorama":{"message":"no_panorama","sender":"delete_upf"},"preview":{"message":"no_preview","sender":"delete_upf"}},"request":{"id":3,"jsonrpc":"2.0","method":"delete_upf","params":{"image_id":"4fd70dfc074340296cc2ebb92158a18d"}}},"id":3,"jsonrpc":"2.0"}
-#[derive(Debug, Serialize, Deserialize)]
-struct ResponseDelete {
-    panorama: bool,
-    preview: bool,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ResponseCapture {
-    capture_available: bool,
-    options: CaptureOptions,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct CaptureOptions {
-    #[serde(rename = "AutoExposure")]
-    auto_exposure: bool,
-    #[serde(rename = "ColorTemperature")]
-    color_temperatue: String,
-    #[serde(rename = "ExposureTime")]
-    exposure_time: f64,
-    #[serde(rename = "ISO")]
-    iso: String,
-    #[serde(rename = "TriggerDelay")]
-    trigger_delay: f64,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct UpfInfo {
-    capture_date: String,
-    image_id: String,
-    preview_url: String,
-    size: u64,
-    upf_url: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Storage {
-    total: u64,
-    usage: u64,
-}
-
-fn send<T: Debug + DeserializeOwned>(
-    client: &mut Client<TcpStream>,
-    req_id: &mut u32,
-    method: Method,
-) -> Result<T> {
-    *req_id += 1;
-    let id = *req_id;
-    let text = serde_json::to_string(&Request {
-        id,
-        method,
-        jsonrpc: "2.0",
-    })?;
-    client.send_message(&Message::text(text))?;
+/// Listen for cameras announcing themselves over SSDP for up to `timeout`. With `stop_early` the
+/// search ends shortly after the first camera is found, leaving just enough time for any others on
+/// the network to answer.
+#[cfg(feature = "ssdp")]
+#[tokio::main(flavor = "current_thread")]
+async fn discover_cameras(timeout: Duration, stop_early: bool) -> Result<Vec<String>> {
+    use cotton_ssdp::{AsyncService, Notification};
+    use futures::StreamExt;
+    use tokio::time::{interval_at, sleep_until, Instant};
+
+    /// How long to keep listening for other cameras after the first is found
+    const LINGER: Duration = Duration::from_secs(2);
+    const PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+
+    let mut netif = cotton_netif::get_interfaces_async()?;
+    let mut ssdp = AsyncService::new()?;
+
+    let mut stream = ssdp.subscribe("panono:ball-camera");
+    status!("Searching for camera...");
+    let mut deadline = Instant::now() + timeout;
+    let mut progress = interval_at(Instant::now() + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+    let mut locations = vec![];
+    loop {
+        tokio::select! {
+            notification = stream.next() => {
+                if let Some(Notification::Alive { location, .. }) = notification {
+                    if !locations.contains(&location) {
+                        status!("Camera found at {location}");
+                        locations.push(location);
+                        if stop_early {
+                            deadline = deadline.min(Instant::now() + LINGER);
+                        }
+                    }
+                }
+            },
+            e = netif.next() => {
+                if let Some(Ok(event)) = e {
+                    ssdp.on_network_event(&event);
+                }
+            }
+            _ = progress.tick() => {
+                if locations.is_empty() {
+                    status!("still searching...");
+                }
+            }
+            _ = sleep_until(deadline) => break,
+        }
+    }
+    Ok(locations)
+}
+
+/// Search for cameras repeatedly until one turns up or `wait` has passed, backing off between
+/// searches. For setups where the camera is switched on after the tool is started.
+#[cfg(feature = "ssdp")]
+fn wait_for_cameras(timeout: Duration, wait: Duration) -> Result<Vec<String>> {
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    let start = Instant::now();
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        // the network may not be up yet either, so keep trying through errors
+        let error = match discover_cameras(timeout, true) {
+            Ok(locations) if !locations.is_empty() => return Ok(locations),
+            Ok(_) => None,
+            Err(e) => Some(e),
+        };
+        let remaining = wait.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return match error {
+                Some(e) => Err(e),
+                None => Ok(vec![]),
+            };
+        }
+        if let Some(e) = error {
+            eprintln!("Discovery failed: {e:#}");
+        }
+        let delay = backoff.min(remaining);
+        status!(
+            "No camera yet after {}s, searching again in {}s",
+            start.elapsed().as_secs(),
+            delay.as_secs_f32().ceil()
+        );
+        thread::sleep(delay);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Find a camera with SSDP, asking which to use if there are several. With `wait` the search is
+/// repeated until a camera shows up or that much time has passed.
+#[cfg(feature = "ssdp")]
+fn find_camera(timeout: Duration, wait: Option<Duration>) -> Result<String> {
+    let locations = match wait {
+        Some(wait) => wait_for_cameras(timeout, wait)?,
+        None => discover_cameras(timeout, true)?,
+    };
+    if locations.is_empty() {
+        bail!(
+            "No camera found via SSDP within {}s. Check it is on and on the same network, or pass its address",
+            wait.unwrap_or(timeout).as_secs()
+        );
+    }
+    pick_camera(locations)
+}
+
+/// Find a camera by probing its default address and then `subnet` for the websocket port, for
+/// networks that block SSDP multicast
+fn scan_for_camera(subnet: Option<Subnet>) -> Result<String> {
+    status!("Probing for a camera...");
+    let locations = scan::find_cameras(subnet);
+    if locations.is_empty() {
+        match subnet {
+            Some(_) => bail!("No camera found at its default address or in the scanned subnet"),
+            None => bail!("No camera found at its default address, pass --scan-subnet to search further or pass its address"),
+        }
+    }
+    pick_camera(locations)
+}
+
+/// Ask which camera to use if more than one was found
+fn pick_camera(mut locations: Vec<String>) -> Result<String> {
+    if locations.len() == 1 {
+        return Ok(locations.remove(0));
+    }
+    for (i, location) in locations.iter().enumerate() {
+        println!("{}: {location}", i + 1);
+    }
+    loop {
+        print!("Pick a camera [1-{}]: ", locations.len());
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            bail!("No camera picked");
+        }
+        match line.trim().parse::<usize>() {
+            Ok(n) if (1..=locations.len()).contains(&n) => return Ok(locations.remove(n - 1)),
+            _ => println!("Expected a number between 1 and {}", locations.len()),
+        }
+    }
+}
+
+/// 3rd party REPL for Panono 360 Camera
+///
+/// Defaults for most flags can be set in config.json in the panonoctl config directory
+/// (~/.config/panonoctl on Linux)
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Websocket address for the camera. If ommitted, it is read from PANONO_ADDRESS or the config
+    /// file, and failing that located with SSDP
+    /// Example on WiFi: ws://192.168.80.80:12345/8086 (use wss:// for TLS)
+    #[arg(env = "PANONO_ADDRESS")]
+    address: Option<String>,
+
+    /// Define a REPL alias for a command and optionally its first arguments, e.g.
+    /// `--alias "dl=download --previews"`. Can be repeated and replaces the built-in aliases of the
+    /// same name
+    #[arg(long = "alias", value_name = "NAME=COMMAND")]
+    aliases: Vec<String>,
+
+    /// Another camera to connect to, can be repeated. Switch between cameras in the REPL with
+    /// `use <n>`
+    #[arg(long = "address", id = "extra_address", value_name = "ADDRESS")]
+    extra_addresses: Vec<String>,
+
+    /// Seconds to wait for a response from the camera before giving up (0 to wait forever)
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+
+    /// Name this client identifies itself with when authenticating. Keeping it stable lets the
+    /// camera recognize the client across reconnects
+    #[arg(long, default_value = "test")]
+    device_name: String,
+
+    /// Value of the auth `force` field, which controls whether an existing session on the camera is
+    /// taken over
+    #[arg(long, default_value = "test")]
+    force: String,
+
+    /// Don't save the camera's auth token for reuse on the next connection
+    #[arg(long)]
+    no_save_token: bool,
+
+    /// Delete the saved auth token for the camera before connecting, so it authenticates afresh.
+    /// Use this when the camera rejects a stale token
+    #[arg(long)]
+    forget_token: bool,
+
+    /// Skip certificate verification for wss:// addresses (for cameras with self-signed certs)
+    #[arg(long)]
+    insecure_tls: bool,
+
+    /// Exit instead of reconnecting when the camera closes the connection
+    #[arg(long)]
+    no_reconnect: bool,
+
+    /// Directory to download UPFs to
+    #[arg(long, default_value = "upfs")]
+    output_dir: String,
+
+    /// How to name downloaded files, without the extension. {image_id}, {short_id} and
+    /// {capture_date} are filled in, the date with an optional strftime format like
+    /// {capture_date:%Y/%m/%d}. Slashes make subdirectories
+    #[arg(long, default_value = "{image_id}")]
+    name_template: NameTemplate,
+
+    /// Number of files to download concurrently
+    #[arg(long, default_value_t = 4)]
+    jobs: usize,
+
+    /// Number of times to retry a download after a network error
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Seconds between checks for new UPFs in capture_and_download and watch, and for the camera
+    /// to become ready in wait_ready
+    #[arg(long, default_value_t = 2)]
+    poll_interval: u64,
+
+    /// Seconds to wait for the new UPF in capture_and_download, or for the camera in wait_ready,
+    /// before giving up
+    #[arg(long, default_value_t = 120)]
+    capture_timeout: u64,
+
+    /// Fetch the camera status in the background every this many seconds, keeping the capture
+    /// availability and battery level in the prompt current
+    #[arg(long, value_name = "SECS")]
+    poll_status: Option<u64>,
+
+    /// Print command results as JSON instead of debug output
+    #[arg(long)]
+    json: bool,
+
+    /// Don't color errors and warnings. Colors are also left out when stderr isn't a terminal or
+    /// the NO_COLOR environment variable is set
+    #[arg(long)]
+    no_color: bool,
+
+    /// Only print command results and errors, leaving out progress bars, the auth details and
+    /// status messages. Combine with --json for output that can be piped
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Report the progress of watch, download and capture_and_download as one JSON object per line
+    /// on stdout (upf_discovered, download_started, download_progress, download_complete, error),
+    /// for monitoring tools. Implies --quiet
+    #[arg(long)]
+    events: bool,
+
+    /// Log more detail to stderr: -v for connection events, -vv for every packet sent and received.
+    /// RUST_LOG overrides this
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Append every raw JSON message sent and received to this file, regardless of verbosity
+    #[arg(long)]
+    trace_file: Option<String>,
+
+    /// Print a completion script for the given shell and exit
+    #[arg(long, value_name = "SHELL")]
+    generate_completions: Option<completions::Shell>,
+
+    /// List the cameras found with SSDP and exit without connecting
+    #[arg(long)]
+    discover: bool,
+
+    /// Seconds to search for cameras with SSDP before giving up
+    #[arg(long, default_value_t = 10)]
+    discovery_timeout: u64,
+
+    /// When SSDP finds no camera, probe every host in this subnet (e.g. 192.168.80.0/24) for the
+    /// camera's websocket port. The camera's default address is always tried first
+    #[arg(long, value_name = "CIDR", conflicts_with = "address")]
+    scan_subnet: Option<Subnet>,
+
+    /// Keep searching for a camera with SSDP for up to this many seconds, retrying with a growing
+    /// delay between searches. Useful when the camera is switched on after starting
+    #[arg(long, value_name = "SECONDS", conflicts_with = "address")]
+    wait_for_camera: Option<u64>,
+
+    /// Run a single command and exit instead of starting the REPL (requires an address)
+    #[arg(requires = "address")]
+    command: Option<String>,
+
+    /// Arguments for the command
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+/// Expand a leading `~` to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), dirs::home_dir()) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            home.join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Where a file downloaded for a UPF is saved
+fn download_path(
+    output_dir: &Path,
+    names: &NameTemplate,
+    upf: &UpfInfo,
+    extension: &str,
+) -> PathBuf {
+    let name = names.render(&upf.image_id, &upf.capture_date);
+    output_dir.join(format!("{name}.{extension}"))
+}
+
+/// The UPF or preview already downloaded for `upf`, if any. Previews are named after whatever
+/// image type the camera served, so every extension that could have been picked is checked.
+fn existing_download(
+    output_dir: &Path,
+    names: &NameTemplate,
+    upf: &UpfInfo,
+    previews: bool,
+) -> Option<PathBuf> {
+    let extensions: &[&str] = if previews {
+        &IMAGE_EXTENSIONS
+    } else {
+        &["upf"]
+    };
+    extensions
+        .iter()
+        .map(|extension| download_path(output_dir, names, upf, extension))
+        .find(|path| path.exists())
+}
+
+/// Plan downloads of the UPF or preview of each UPF to `output_dir`, named by `names`, skipping
+/// any that already exist
+fn plan_downloads(
+    upfs: &[&UpfInfo],
+    output_dir: &Path,
+    names: &NameTemplate,
+    previews: bool,
+) -> Vec<Download> {
+    let mut downloads = vec![];
+    for upf in upfs {
+        if let Some(path) = existing_download(output_dir, names, upf, previews) {
+            status!("{} already exists, skipping...", path.display());
+            continue;
+        }
+        downloads.push(if previews {
+            Download {
+                image_id: upf.image_id.clone(),
+                url: upf.preview_url.clone(),
+                size: None,
+                // named .jpg until the camera says otherwise
+                path: download_path(output_dir, names, upf, "jpg"),
+                detect_image_type: true,
+            }
+        } else {
+            Download {
+                image_id: upf.image_id.clone(),
+                url: upf.upf_url.clone(),
+                size: Some(upf.size),
+                path: download_path(output_dir, names, upf, "upf"),
+                detect_image_type: false,
+            }
+        });
+    }
+    downloads
+}
+
+/// Download the given UPFs to `output_dir`, skipping any that already exist. With `dry_run` only
+/// report what would be downloaded.
+fn download_upfs(
+    upfs: &[&UpfInfo],
+    output_dir: &Path,
+    names: &NameTemplate,
+    jobs: usize,
+    retries: u32,
+    max_rate: Option<u64>,
+    dry_run: bool,
+) -> Result<()> {
+    let downloads = plan_downloads(upfs, output_dir, names, false);
+    if dry_run {
+        for download in &downloads {
+            println!(
+                "would download {} ({})",
+                download.path.display(),
+                HumanBytes(download.size.unwrap_or(0))
+            );
+        }
+        let total = downloads.iter().filter_map(|d| d.size).sum::<u64>();
+        println!(
+            "would download {} UPFs, {} total",
+            downloads.len(),
+            HumanBytes(total)
+        );
+        return Ok(());
+    }
+    run_downloads(downloads, output_dir, jobs, retries, max_rate, |done| {
+        if done.is_empty() {
+            return Ok(());
+        }
+        let mut manifest = Manifest::load(output_dir)?;
+        for (download, sha256) in done {
+            if let Some(upf) = upfs.iter().find(|u| u.image_id == download.image_id) {
+                manifest.insert(ManifestEntry::new(upf, sha256.clone()));
+            }
+        }
+        manifest.save(output_dir)
+    })
+}
+
+/// Download the preview images of the given UPFs to `output_dir`, skipping any that already exist
+fn download_previews(
+    upfs: &[&UpfInfo],
+    output_dir: &Path,
+    names: &NameTemplate,
+    jobs: usize,
+    retries: u32,
+    max_rate: Option<u64>,
+) -> Result<()> {
+    let downloads = plan_downloads(upfs, output_dir, names, true);
+    run_downloads(downloads, output_dir, jobs, retries, max_rate, |_| Ok(()))
+}
+
+/// UPFs downloaded to `output_dir` by image ID: those recorded in the manifest whose file is still
+/// there, plus any `<image_id>.upf` downloaded before the manifest was kept
+fn local_upfs(output_dir: &Path, names: &NameTemplate) -> Result<BTreeMap<String, PathBuf>> {
+    let mut local = BTreeMap::new();
+    for entry in Manifest::load(output_dir)?.upfs {
+        let named = DateTime::parse_from_rfc3339(&entry.capture_date)
+            .ok()
+            .map(|date| names.render(&entry.image_id, &date));
+        let path = [named.as_deref(), Some(entry.image_id.as_str())]
+            .into_iter()
+            .flatten()
+            .map(|name| output_dir.join(format!("{name}.upf")))
+            .find(|path| path.exists());
+        if let Some(path) = path {
+            local.insert(entry.image_id, path);
+        }
+    }
+    let Ok(dir) = fs::read_dir(output_dir) else {
+        return Ok(local);
+    };
+    let recorded = local.values().cloned().collect::<HashSet<_>>();
+    for file in dir {
+        let path = file?.path();
+        if path.extension().is_some_and(|e| e == "upf") && !recorded.contains(&path) {
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+                local.entry(id.to_string()).or_insert(path);
+            }
+        }
+    }
+    Ok(local)
+}
+
+/// What is on the camera but not downloaded, and downloaded but gone from the camera
+#[derive(Debug, Serialize)]
+struct UpfDiff<'a> {
+    camera_only: Vec<&'a UpfInfo>,
+    local_only: BTreeMap<String, PathBuf>,
+}
+
+fn diff_upfs<'a>(camera: &'a [UpfInfo], mut local: BTreeMap<String, PathBuf>) -> UpfDiff<'a> {
+    let camera_only = camera
+        .iter()
+        .filter(|u| local.remove(&u.image_id).is_none())
+        .collect();
+    UpfDiff {
+        camera_only,
+        local_only: local,
+    }
+}
+
+/// Rename downloads in `output_dir` that are still named by image ID to match `names`, returning
+/// how many were moved. Capture dates come from the manifest, so only UPFs recorded there and their
+/// previews are found.
+fn organize(output_dir: &Path, names: &NameTemplate) -> Result<usize> {
+    let manifest = Manifest::load(output_dir)?;
+    let mut renamed = 0;
+    for entry in &manifest.upfs {
+        let capture_date =
+            DateTime::parse_from_rfc3339(&entry.capture_date).with_context(|| {
+                format!(
+                    "Invalid capture date {:?} for {} in the manifest",
+                    entry.capture_date, entry.image_id
+                )
+            })?;
+        let name = names.render(&entry.image_id, &capture_date);
+        for extension in std::iter::once("upf").chain(IMAGE_EXTENSIONS) {
+            let from = output_dir.join(format!("{}.{extension}", entry.image_id));
+            let to = output_dir.join(format!("{name}.{extension}"));
+            if from == to || !from.exists() {
+                continue;
+            }
+            if to.exists() {
+                println!(
+                    "{} already exists, leaving {}",
+                    to.display(),
+                    from.display()
+                );
+                continue;
+            }
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+            fs::rename(&from, &to).with_context(|| format!("Failed to move {}", from.display()))?;
+            println!("{} -> {}", from.display(), to.display());
+            renamed += 1;
+        }
+    }
+    Ok(renamed)
+}
+
+/// Download everything, then pass the downloads that succeeded and their SHA-256 to `completed`
+/// before reporting any that failed
+fn run_downloads(
+    downloads: Vec<Download>,
+    output_dir: &Path,
+    jobs: usize,
+    retries: u32,
+    max_rate: Option<u64>,
+    completed: impl FnOnce(&[(Download, String)]) -> Result<()>,
+) -> Result<()> {
+    // the name template may put files in subdirectories
+    let dirs = downloads.iter().filter_map(|d| d.path.parent());
+    for dir in std::iter::once(output_dir).chain(dirs) {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let total = downloads.len();
+    let (mut done, mut failed) = (vec![], vec![]);
+    for (download, res) in download_all(downloads, jobs, retries, max_rate) {
+        match res {
+            Ok(sha256) => done.push((download, sha256)),
+            Err(e) => failed.push((download, e)),
+        }
+    }
+    completed(&done)?;
+    // with --events each failure was already reported as it happened
+    if !events::enabled() {
+        for (download, e) in &failed {
+            println!("failed to download {}: {e:#}", download.image_id);
+        }
+    }
+    if !failed.is_empty() {
+        bail!("{} of {} downloads failed", failed.len(), total);
+    }
+    Ok(())
+}
+
+/// Point out when the camera is out of space, since captures then fail without saying why
+fn warn_if_full(res: &ResponseGetUpfInfos) {
+    if res.is_full {
+        eprintln!(
+            "{}",
+            color::warning(
+                "WARNING: camera storage is full, new captures will fail until panoramas are deleted (see delete and delete_all)"
+            )
+        );
+    }
+}
+
+/// Column to sort the UPF listing by
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UpfSort {
+    Date,
+    Size,
+    Id,
+}
+
+impl std::str::FromStr for UpfSort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "date" => Ok(Self::Date),
+            "size" => Ok(Self::Size),
+            "id" => Ok(Self::Id),
+            _ => bail!("Expected date, size or id, got {s:?}"),
+        }
+    }
+}
+
+/// UPFs in the order the listing is shown in
+fn sorted_upfs(upfs: &[UpfInfo], sort: UpfSort) -> Vec<&UpfInfo> {
+    let mut upfs = upfs.iter().collect::<Vec<_>>();
+    match sort {
+        UpfSort::Date => upfs.sort_by_key(|u| u.capture_date),
+        UpfSort::Size => upfs.sort_by_key(|u| u.size),
+        UpfSort::Id => upfs.sort_by_key(|u| &u.image_id),
+    }
+    upfs
+}
+
+/// Print UPFs as an aligned table with a total size footer
+fn print_upf_table(upfs: &[UpfInfo], sort: UpfSort) {
+    let upfs = sorted_upfs(upfs, sort);
+    let header = ["DATE", "ID", "SIZE", "URL"];
+    let rows = upfs
+        .iter()
+        .map(|u| {
+            [
+                format_timestamp(&u.capture_date),
+                u.image_id.clone(),
+                HumanBytes(u.size).to_string(),
+                u.upf_url.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let [date, id, size, _] = widths;
+    let print_row = |row: [&str; 4]| {
+        println!(
+            "{:date$}  {:id$}  {:>size$}  {}",
+            row[0], row[1], row[2], row[3]
+        )
+    };
+    print_row(header);
+    for row in &rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+    let total = upfs.iter().map(|u| u.size).sum::<u64>();
+    println!("{} UPFs, {} total", upfs.len(), HumanBytes(total));
+}
+
+/// The listing as CSV with a header row, sizes in bytes, for importing into a spreadsheet
+fn upf_csv(upfs: &[UpfInfo], sort: UpfSort) -> String {
+    // quote fields with separators, quotes or line breaks, doubling any quotes inside
+    let field = |value: &str| {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    };
+    let mut csv = String::from("capture_date,image_id,size,upf_url,preview_url\n");
+    for u in sorted_upfs(upfs, sort) {
+        let row = [
+            format_timestamp(&u.capture_date),
+            u.image_id.clone(),
+            u.size.to_string(),
+            u.upf_url.clone(),
+            u.preview_url.clone(),
+        ];
+        csv += &row.map(|v| field(&v)).join(",");
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Set an option from user input, checking it against the option's type and constraints first so
+/// we don't send something the camera will reject
+fn set_option(
+    client: &mut PanonoClient,
+    name: KnownOption,
+    value: &str,
+) -> Result<serde_json::Value> {
+    let list = client.get_option_list()?;
+    let option = list
+        .options
+        .iter()
+        .find(|o| o.name() == name.as_str())
+        .with_context(|| format!("Unknown option {name}"))?;
+    let value = option.parse_value(value)?;
+    client.set_option(name, value)
+}
+
+fn emit_discovered(upf: &UpfInfo) {
+    events::emit(&Event::UpfDiscovered {
+        image_id: &upf.image_id,
+        capture_date: format_timestamp(&upf.capture_date),
+        size: upf.size,
+    });
+}
+
+/// Set several options from `name=value` pairs. Every value is checked against the option list
+/// before any is sent, so a typo doesn't leave the camera half configured. The camera has no batch
+/// method, so each is still its own request and the ones that fail are reported.
+fn set_options(client: &mut PanonoClient, assignments: &[&str]) -> Result<()> {
+    if assignments.is_empty() {
+        bail!("Expected one or more name=value pairs, e.g. iso=400 auto_exposure=false");
+    }
+    let list = client.get_option_list()?;
+    let mut values = vec![];
+    for assignment in assignments {
+        let (name, value) = assignment
+            .split_once('=')
+            .with_context(|| format!("Expected name=value, got {assignment:?}"))?;
+        let name = name.parse::<KnownOption>().unwrap();
+        let option = list
+            .options
+            .iter()
+            .find(|o| o.name() == name.as_str())
+            .with_context(|| format!("Unknown option {name}"))?;
+        values.push((name, option.parse_value(value)?));
+    }
+
+    let mut failed = 0;
+    for (name, value) in values {
+        match client.set_option(name.clone(), value.clone()) {
+            Ok(_) => println!("{name}: {value}"),
+            Err(e) => {
+                println!("failed to set {name}: {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} options could not be set");
+    }
+    Ok(())
+}
+
+/// Write every option's type, constraints and current value to `path` as JSON keyed by option
+/// name, returning how many were written
+fn export_options(client: &mut PanonoClient, path: &Path) -> Result<usize> {
+    let list = client.get_option_list()?;
+    let values = client.get_options()?.options;
+    let mut options = serde_json::Map::new();
+    for option in list.options {
+        let name = option.name().to_string();
+        let mut entry = serde_json::to_value(&option)?;
+        if let Some(entry) = entry.as_object_mut() {
+            entry.remove("name");
+            if let Some(value) = values.get(&name) {
+                entry.insert("value".into(), serde_json::to_value(value)?);
+            }
+        }
+        options.insert(name, entry);
+    }
+    let count = options.len();
+    fs::write(path, serde_json::to_string_pretty(&options)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(count)
+}
+
+/// An option as written by `export_options`. Only the value is needed to restore it, the type and
+/// constraints are there for reading.
+#[derive(Debug, Deserialize)]
+struct ExportedOption {
+    value: Option<StringOrNumber>,
+}
+
+/// Set every option saved in `path` by `export_options` that differs from the camera's current
+/// value, checking each against the camera's constraints first. Options that can't be set are
+/// reported and skipped so one bad value doesn't stop the rest.
+fn import_options(client: &mut PanonoClient, path: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let saved: BTreeMap<String, ExportedOption> = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let list = client.get_option_list()?;
+    let current = client.get_options()?.options;
 
-    #[derive(Debug)]
-    enum PacketIncoming<'a> {
-        Response(Response<'a>),
-        IncomingRequest(IncomingRequest<'a>),
+    let mut failed = 0;
+    for (name, saved) in saved {
+        let Some(value) = saved.value else {
+            continue;
+        };
+        if current.get(&name).is_some_and(|c| c.same_value(&value)) {
+            continue;
+        }
+        let res = list
+            .options
+            .iter()
+            .find(|o| o.name() == name)
+            .with_context(|| format!("Unknown option {name}"))
+            .and_then(|option| option.parse_value(&value.to_string()))
+            .and_then(|value| client.set_option(KnownOption::from(name.clone()), value));
+        match res {
+            Ok(_) => match current.get(&name) {
+                Some(old) => println!("{name}: {old} -> {value}"),
+                None => println!("{name}: {value}"),
+            },
+            Err(e) => {
+                println!("failed to set {name}: {e:#}");
+                failed += 1;
+            }
+        }
     }
+    if failed > 0 {
+        bail!("{failed} options could not be set");
+    }
+    Ok(())
+}
 
+/// Poll the camera until UPFs not in `known` show up. The lock is only held while asking so the
+/// keepalive can run in between.
+fn wait_for_new_upfs(
+    client: &Mutex<PanonoClient>,
+    known: &HashSet<String>,
+    interval: Duration,
+    timeout: Duration,
+) -> Result<Vec<UpfInfo>> {
+    let deadline = Instant::now() + timeout;
     loop {
-        for text in recv(client)?.lines() {
-            let res = serde_json::from_str(text)
-                .map(PacketIncoming::Response)
-                .or_else(|_| serde_json::from_str(text).map(PacketIncoming::IncomingRequest))
-                .with_context(|| format!("Error parsing packet {}", &text))?;
+        let res = client.lock().unwrap().get_upf_infos()?;
+        let new = res
+            .upf_infos
+            .into_iter()
+            .filter(|u| !known.contains(&u.image_id))
+            .collect::<Vec<_>>();
+        if !new.is_empty() {
+            for upf in &new {
+                emit_discovered(upf);
+            }
+            return Ok(new);
+        }
+        if Instant::now() >= deadline {
+            bail!("No new UPF appeared within {}s", timeout.as_secs());
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Set while a command that runs until interrupted is active, so Ctrl-C stops the command instead
+/// of exiting
+static INTERRUPTIBLE: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Short names for the commands used most
+const DEFAULT_ALIASES: [(&str, &str); 3] = [
+    ("ls", "get_upf_infos"),
+    ("dl", "download"),
+    ("st", "get_status"),
+];
+
+/// The connection, for the Ctrl-C handler to close on exit
+static CLIENT: OnceLock<Arc<Mutex<PanonoClient>>> = OnceLock::new();
+
+/// Poll the status until the camera reports capture is available, giving up after `timeout`
+fn wait_ready(client: &Mutex<PanonoClient>, interval: Duration, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let spinner = download::spinner("waiting for the camera to be ready");
+    let res = loop {
+        match client.lock().unwrap().status() {
+            Ok(status) if status.capture_available => break Ok(()),
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+        if Instant::now() >= deadline {
+            break Err(anyhow::anyhow!(
+                "The camera was still busy after {}s",
+                timeout.as_secs()
+            ));
+        }
+        thread::sleep(interval.min(deadline - Instant::now()));
+    };
+    spinner.finish_and_clear();
+    res
+}
+
+/// Sleep for `duration`, returning early with `true` if Ctrl-C is pressed
+fn sleep_interruptible(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100).min(deadline - Instant::now()));
+    }
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Poll the camera until Ctrl-C is pressed, passing each batch of new UPFs to `on_new`
+fn watch(
+    client: &Mutex<PanonoClient>,
+    interval: Duration,
+    mut on_new: impl FnMut(&[UpfInfo]) -> Result<()>,
+) -> Result<()> {
+    let mut seen = client
+        .lock()
+        .unwrap()
+        .get_upf_infos()?
+        .upf_infos
+        .into_iter()
+        .map(|u| u.image_id)
+        .collect::<HashSet<_>>();
+    status!(
+        "watching for new UPFs ({} already on the camera), press Ctrl-C to stop",
+        seen.len()
+    );
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    INTERRUPTIBLE.store(true, Ordering::SeqCst);
+    let res = (|| {
+        while !sleep_interruptible(interval) {
+            let res = client.lock().unwrap().get_upf_infos()?;
+            let new = res
+                .upf_infos
+                .into_iter()
+                .filter(|u| seen.insert(u.image_id.clone()))
+                .collect::<Vec<_>>();
+            for upf in &new {
+                if events::enabled() {
+                    emit_discovered(upf);
+                } else {
+                    println!(
+                        "{}  new UPF {} ({})",
+                        format_timestamp(&upf.capture_date),
+                        upf.image_id,
+                        HumanBytes(upf.size)
+                    );
+                }
+            }
+            if !new.is_empty() {
+                on_new(&new)?;
+            }
+        }
+        status!("stopped watching");
+        Ok(())
+    })();
+    INTERRUPTIBLE.store(false, Ordering::SeqCst);
+    res
+}
+
+/// Round-trip times measured by `ping`, in milliseconds
+#[derive(Debug, PartialEq, Serialize)]
+struct Latency {
+    replies: usize,
+    min_ms: f64,
+    avg_ms: f64,
+    max_ms: f64,
+}
+
+impl Latency {
+    fn new(times: &[Duration]) -> Option<Self> {
+        let ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+        Some(Self {
+            replies: times.len(),
+            min_ms: ms(times.iter().min()?),
+            avg_ms: times.iter().map(ms).sum::<f64>() / times.len() as f64,
+            max_ms: ms(times.iter().max()?),
+        })
+    }
+}
+
+/// Time `count` status requests, `interval` apart, printing each round trip as it completes.
+/// Ctrl-C stops early, keeping the replies so far.
+fn ping(client: &Mutex<PanonoClient>, count: usize, interval: Duration) -> Result<Option<Latency>> {
+    let mut times = vec![];
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    INTERRUPTIBLE.store(true, Ordering::SeqCst);
+    let res = (|| {
+        for i in 1..=count {
+            let start = Instant::now();
+            client.lock().unwrap().status()?;
+            let time = start.elapsed();
+            status!("reply {i}: time={:.1} ms", time.as_secs_f64() * 1000.0);
+            times.push(time);
+            if i < count && sleep_interruptible(interval) {
+                break;
+            }
+        }
+        Ok(())
+    })();
+    INTERRUPTIBLE.store(false, Ordering::SeqCst);
+    res.map(|_| Latency::new(&times))
+}
+
+fn print_latency(latency: Option<Latency>, output: Output) -> Result<()> {
+    match latency {
+        Some(latency) if output.json => output.print(&latency)?,
+        Some(l) => println!(
+            "{} replies, min/avg/max = {:.1}/{:.1}/{:.1} ms",
+            l.replies, l.min_ms, l.avg_ms, l.max_ms
+        ),
+        None => println!("no replies"),
+    }
+    Ok(())
+}
+
+/// Range of capture dates to keep, from the --since and --until flags. Both ends are inclusive.
+#[derive(Debug, Default, PartialEq)]
+struct DateRange {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+impl DateRange {
+    /// Set the bound for `flag` from either a full RFC 3339 timestamp or a plain `YYYY-MM-DD` date,
+    /// which covers the whole day in UTC like the camera's own timestamps
+    fn set(&mut self, flag: &str, value: &str) -> Result<()> {
+        let date = match DateTime::parse_from_rfc3339(value) {
+            Ok(date) => date.with_timezone(&Utc),
+            Err(_) => {
+                let day = NaiveDate::parse_from_str(value, "%Y-%m-%d").with_context(|| {
+                    format!("{flag} expects a date like 2017-06-03 or 2017-06-03T14:20:51Z, got {value:?}")
+                })?;
+                let time = match flag {
+                    "--until" => day.and_hms_opt(23, 59, 59),
+                    _ => day.and_hms_opt(0, 0, 0),
+                };
+                time.unwrap().and_utc()
+            }
+        };
+        match flag {
+            "--since" => self.since = Some(date),
+            _ => self.until = Some(date),
+        }
+        Ok(())
+    }
+
+    /// Drop the UPFs captured outside the range
+    fn retain(&self, upfs: &mut Vec<UpfInfo>) {
+        upfs.retain(|u| {
+            self.since.is_none_or(|since| u.capture_date >= since)
+                && self.until.is_none_or(|until| u.capture_date <= until)
+        });
+    }
+}
+
+/// Flags accepted by the get_upf_infos command
+#[derive(Debug, PartialEq)]
+struct ListOptions {
+    sort: UpfSort,
+    new_only: bool,
+    dates: DateRange,
+    /// From `--format csv`, instead of the table
+    csv: bool,
+}
+
+fn parse_list_options(args: &[&str]) -> Result<ListOptions> {
+    let mut options = ListOptions {
+        sort: UpfSort::Date,
+        new_only: false,
+        dates: DateRange::default(),
+        csv: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--new-only" => options.new_only = true,
+            "--format" => {
+                options.csv = match args.next().copied() {
+                    Some("csv") => true,
+                    Some("table") => false,
+                    other => bail!("Expected --format csv or --format table, got {other:?}"),
+                };
+            }
+            "--sort" => {
+                options.sort = args
+                    .next()
+                    .context("Missing value for --sort, expected date, size or id")?
+                    .parse()?;
+            }
+            "--since" | "--until" => {
+                let value = args
+                    .next()
+                    .with_context(|| format!("Missing value for {arg}"))?;
+                options.dates.set(arg, value)?;
+            }
+            _ => options.sort = arg.parse()?,
+        }
+    }
+    Ok(options)
+}
+
+/// Fail early when the camera has said it can't capture, rather than waiting for it to refuse
+fn ensure_capture_available(client: &PanonoClient) -> Result<()> {
+    if client.capture_available() == Some(false) {
+        bail!("The camera reports capture is not available right now, try again once it is ready");
+    }
+    Ok(())
+}
+
+/// Capture a panorama with a spinner on stderr, since the camera can take a while to respond
+fn capture(client: &mut PanonoClient, options: Option<CaptureOptions>) -> Result<ResponseCapture> {
+    let spinner = download::spinner("capturing");
+    let res = client.capture(options);
+    spinner.finish_and_clear();
+    res
+}
+
+/// Flags accepted by the download command
+#[derive(Debug, Default, PartialEq)]
+struct DownloadOptions {
+    dry_run: bool,
+    previews: bool,
+    /// Cap on the combined download speed in bytes per second
+    max_rate: Option<u64>,
+    dates: DateRange,
+    /// Download at most this many files, after skipping those already downloaded
+    limit: Option<usize>,
+    /// Take the oldest captures first instead of the newest, for --limit
+    oldest: bool,
+}
+
+fn parse_download_options(args: &[&str]) -> Result<DownloadOptions> {
+    let mut options = DownloadOptions::default();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        match *flag {
+            "--dry-run" => options.dry_run = true,
+            "--previews" => options.previews = true,
+            "--max-rate" => {
+                let value = args.next().context("Missing value for --max-rate")?;
+                options.max_rate = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|rate| *rate > 0)
+                        .with_context(|| {
+                            format!("--max-rate expects a positive number of bytes per second, got {value:?}")
+                        })?,
+                );
+            }
+            "--since" | "--until" => {
+                let value = args
+                    .next()
+                    .with_context(|| format!("Missing value for {flag}"))?;
+                options.dates.set(flag, value)?;
+            }
+            "--limit" => {
+                let value = args.next().context("Missing value for --limit")?;
+                options.limit = Some(value.parse().with_context(|| {
+                    format!("--limit expects a number of files, got {value:?}")
+                })?);
+            }
+            "--newest" => options.oldest = false,
+            "--oldest" => options.oldest = true,
+            _ => bail!("Unknown download option {flag}"),
+        }
+    }
+    if options.dry_run && options.previews {
+        bail!("--dry-run only applies to UPFs, not previews");
+    }
+    Ok(options)
+}
+
+/// Parse `--flag value` pairs overriding capture settings
+fn parse_capture_options(args: &[&str]) -> Result<CaptureOptions> {
+    let mut options = CaptureOptions::default();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .with_context(|| format!("Missing value for {flag}"))?;
+        match *flag {
+            "--iso" => options.iso = Some(value.to_string()),
+            "--exposure" => {
+                options.exposure_time = Some(parse_number(value).with_context(|| {
+                    format!("--exposure expects seconds or a fraction like 1/100, got {value:?}")
+                })?);
+            }
+            "--trigger-delay" => {
+                options.trigger_delay =
+                    Some(value.parse().with_context(|| {
+                        format!("--trigger-delay expects seconds, got {value:?}")
+                    })?)
+            }
+            "--color-temp" => options.color_temperature = Some(value.to_string()),
+            "--auto-exposure" => {
+                options.auto_exposure = Some(value.parse().with_context(|| {
+                    format!("--auto-exposure expects true or false, got {value:?}")
+                })?)
+            }
+            _ => bail!("Unknown capture option {flag}"),
+        }
+    }
+    Ok(options)
+}
+
+/// Open a URL with the platform's default handler
+fn open_in_browser(url: &str) -> Result<()> {
+    webbrowser::open(url).with_context(|| format!("Failed to open {url} in a browser"))
+}
+
+/// Ask the user to type YES before doing something destructive
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{prompt} Type YES to confirm: ");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let confirmed = line.trim() == "YES";
+    if !confirmed {
+        println!("aborted");
+    }
+    Ok(confirmed)
+}
+
+/// Delete a UPF, explaining the camera's error when neither file could be deleted
+fn delete_upf(client: &mut PanonoClient, image_id: String) -> Result<ResponseDelete> {
+    client.delete_upf(image_id).map_err(|e| {
+        match e
+            .downcast_ref::<ResponseError>()
+            .and_then(ResponseError::delete_failure)
+        {
+            Some(explanation) => e.context(explanation),
+            None => e,
+        }
+    })
+}
+
+/// Delete each UPF, carrying on past failures and reporting them at the end
+fn delete_all(client: &mut PanonoClient, upfs: &[UpfInfo]) -> Result<()> {
+    let mut failed = 0;
+    for (i, upf) in upfs.iter().enumerate() {
+        match delete_upf(client, upf.image_id.clone()) {
+            Ok(res) => println!("[{}/{}] {}: {res}", i + 1, upfs.len(), upf.image_id),
+            Err(e) => {
+                failed += 1;
+                println!(
+                    "[{}/{}] failed to delete {}: {e:#}",
+                    i + 1,
+                    upfs.len(),
+                    upf.image_id
+                );
+            }
+        }
+    }
+    println!("deleted {} of {} UPFs", upfs.len() - failed, upfs.len());
+    if failed > 0 {
+        bail!("{failed} deletions failed");
+    }
+    Ok(())
+}
+
+/// How command results are printed
+#[derive(Debug, Clone, Copy)]
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn print<T: Debug + Serialize>(&self, value: &T) -> Result<()> {
+        if events::enabled() {
+            events::emit(&Event::Result {
+                result: serde_json::to_value(value)?,
+            });
+        } else if self.json {
+            println!("{}", serde_json::to_string_pretty(value)?);
+        } else {
+            println!("{:#?}", value);
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTIBLE.load(Ordering::SeqCst) {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        } else {
+            // a request in flight holds the lock, don't wait for it
+            if let Some(Ok(mut client)) = CLIENT.get().map(|c| c.try_lock()) {
+                let _ = client.close();
+            }
+            std::process::exit(130);
+        }
+    })?;
+
+    let config = Config::load()?;
+    let matches = config.apply(Args::command()).get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+
+    if let Some(shell) = args.generate_completions {
+        return Ok(completions::generate(
+            shell,
+            Args::command(),
+            &mut std::io::stdout(),
+        )?);
+    }
+
+    // The address comes from the command line, then PANONO_ADDRESS, then the config file
+    let fallback = std::env::var("PANONO_ADDRESS").ok().or(config.address);
+    match (&args.address, fallback) {
+        // Positionals are filled in order, so with the address coming from elsewhere a one-shot
+        // command ends up in the address slot. Addresses always have a scheme, so shift everything
+        // back by one.
+        (Some(address), Some(fallback)) if !address.contains("://") => {
+            if let Some(command) = args.command.take() {
+                args.args.insert(0, command);
+            }
+            args.command = args.address.replace(fallback);
+        }
+        (None, fallback) => args.address = fallback,
+        _ => {}
+    }
+
+    if args.events {
+        events::enable();
+        args.quiet = true;
+    }
+    if args.quiet {
+        QUIET.store(true, Ordering::Relaxed);
+        download::hide_progress();
+    }
+
+    color::init(args.no_color);
+    env_logger::Builder::new()
+        .format(|buf, record| {
+            let line = format!(
+                "[{} {:<5} {}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.module_path().unwrap_or_default(),
+                record.args()
+            );
+            // makes a warning from the camera, e.g. low battery, stand out from debug output
+            match record.level() {
+                log::Level::Error => writeln!(buf, "{}", color::error(line)),
+                log::Level::Warn => writeln!(buf, "{}", color::warning(line)),
+                _ => writeln!(buf, "{line}"),
+            }
+        })
+        .filter_level(match args.verbose {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            2 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        })
+        .parse_default_env()
+        .init();
+
+    if args.discover {
+        #[cfg(feature = "ssdp")]
+        {
+            let locations = discover_cameras(Duration::from_secs(args.discovery_timeout), false)?;
+            if locations.is_empty() {
+                println!("No cameras found");
+            }
+            for (i, location) in locations.iter().enumerate() {
+                println!("{}: {location}", i + 1);
+            }
+            return Ok(());
+        }
+        #[cfg(not(feature = "ssdp"))]
+        {
+            bail!("Discovery requires the \"ssdp\" feature (Linux only)")
+        }
+    }
+
+    let address = match args.address {
+        Some(address) => {
+            if !args.quiet {
+                eprintln!("Connecting to {}", address);
+            }
+            address
+        }
+        None => {
+            #[cfg(feature = "ssdp")]
+            {
+                match find_camera(
+                    Duration::from_secs(args.discovery_timeout),
+                    args.wait_for_camera.map(Duration::from_secs),
+                ) {
+                    Ok(address) => address,
+                    Err(e) => {
+                        eprintln!("{e:#}");
+                        scan_for_camera(args.scan_subnet)?
+                    }
+                }
+            }
+            #[cfg(not(feature = "ssdp"))]
+            {
+                scan_for_camera(args.scan_subnet).context(
+                    "Automatic discovery (\"ssdp\" feature, Linux only) is disabled. See --help to specify manually",
+                )?
+            }
+        }
+    };
+
+    let output_dir = Rc::new(RefCell::new(expand_home(&args.output_dir)));
+    let name_template = Rc::new(args.name_template.clone());
+
+    let connector = Connector {
+        insecure_tls: args.insecure_tls,
+        reconnect: !args.no_reconnect,
+        timeout: (args.timeout > 0).then(|| Duration::from_secs(args.timeout)),
+        trace_file: args.trace_file.as_deref().map(expand_home),
+        device_name: args.device_name.clone(),
+        force: args.force.clone(),
+        save_token: !args.no_save_token,
+        forget_token: args.forget_token,
+    };
+    let output = Output { json: args.json };
+
+    let (client, auth) = connector.open(&address)?;
+    download::set_auth_token(client.auth_token().map(String::from));
+    // keep stdout clean for the result of a one-shot command
+    if args.command.is_none() && !args.quiet {
+        output.print(&auth)?;
+    }
+    let cameras = Rc::new(RefCell::new(Cameras::new(client, address)));
+    for address in &args.extra_addresses {
+        if !args.quiet {
+            eprintln!("Connecting to {}", address);
+        }
+        let (client, _) = connector.open(address)?;
+        let number = cameras.borrow_mut().add(client, address.clone());
+        if args.command.is_none() {
+            status!("connected to camera {number} at {address}");
+        }
+    }
+    let client = cameras.borrow().active();
+    let _ = CLIENT.set(client.clone());
+    // a one-shot command has no prompt to keep current
+    if let (Some(secs), None) = (args.poll_status.filter(|s| *s > 0), &args.command) {
+        // follows whichever camera is in use, see `Cameras::switch`
+        spawn_status_poller(client.clone(), Duration::from_secs(secs));
+    }
+
+    use easy_repl::{command, Command, CommandStatus};
+
+    let mut commands: Vec<(&str, Command)> = vec![];
+
+    let c = client.clone();
+    commands.push((
+        "delete",
+        command! {
+            "Delete UPF by ID",
+            (id: String) => |image_id| {
+                let res = delete_upf(&mut c.lock().unwrap(), image_id)?;
+                if output.json {
+                    output.print(&res)?;
+                } else {
+                    println!("{res}");
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let dir = output_dir.clone();
+    commands.push((
+        "set_output_dir",
+        command! {
+            "Set the directory UPFs are downloaded to",
+            (path: String) => |path: String| {
+                *dir.borrow_mut() = expand_home(&path);
+                status!("downloading to {}", dir.borrow().display());
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let dir = output_dir.clone();
+    let names = name_template.clone();
+    commands.push((
+        "organize",
+        command! {
+            "Rename downloads still named by image ID to match --name-template, using manifest.json",
+            () => || {
+                let renamed = organize(&dir.borrow(), &names)?;
+                status!("renamed {renamed} files");
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let dir = output_dir.clone();
+    let names = name_template.clone();
+    let c = client.clone();
+    commands.push((
+        "diff",
+        command! {
+            "List UPFs on the camera that aren't downloaded yet, and downloads no longer on the camera",
+            () => || {
+                let res = c.lock().unwrap().get_upf_infos()?;
+                let diff = diff_upfs(&res.upf_infos, local_upfs(&dir.borrow(), &names)?);
+                if output.json {
+                    output.print(&diff)?;
+                    return Ok(CommandStatus::Done);
+                }
+                println!("on the camera but not downloaded ({}):", diff.camera_only.len());
+                for upf in &diff.camera_only {
+                    println!(
+                        "  {}  {}  {}",
+                        format_timestamp(&upf.capture_date),
+                        upf.image_id,
+                        HumanBytes(upf.size)
+                    );
+                }
+                println!("downloaded but no longer on the camera ({}):", diff.local_only.len());
+                for (image_id, path) in &diff.local_only {
+                    println!("  {image_id}  {}", path.display());
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "delete_all",
+        command! {
+            "Delete every UPF on the camera after confirmation",
+            () => || {
+                let res = c.lock().unwrap().get_upf_infos()?;
+                if res.upf_infos.is_empty() {
+                    println!("no UPFs to delete");
+                } else if confirm(&format!("Delete all {} UPFs from the camera?", res.upf_infos.len()))? {
+                    delete_all(&mut c.lock().unwrap(), &res.upf_infos)?;
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "delete_all",
+        command! {
+            "Delete every UPF on the camera without asking (pass --yes)",
+            (yes: String) => |yes: String| {
+                if yes != "--yes" {
+                    bail!("Expected --yes, got {yes:?}");
+                }
+                let mut client = c.lock().unwrap();
+                let res = client.get_upf_infos()?;
+                delete_all(&mut client, &res.upf_infos)?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let jobs = args.jobs;
+    let retries = args.retries;
+    let dir = output_dir.clone();
+    let names = name_template.clone();
+    let c = client.clone();
+    commands.push((
+        "download",
+        Command {
+            description: "Download any new UPFs, or just preview what would be fetched (--dry-run) or download the preview JPEGs (--previews)".into(),
+            args_info: [
+                "[--dry-run]",
+                "[--previews]",
+                "[--max-rate <bytes/s>]",
+                "[--since <date>]",
+                "[--until <date>]",
+                "[--limit <n> [--newest|--oldest]]",
+            ]
+            .map(String::from)
+            .to_vec(),
+            handler: Box::new(move |args| {
+                let options = parse_download_options(args)?;
+                let mut res = c.lock().unwrap().get_upf_infos()?;
+                warn_if_full(&res);
+                options.dates.retain(&mut res.upf_infos);
+                let mut upfs = res.upf_infos.iter().collect::<Vec<_>>();
+                let dir = dir.borrow();
+                if let Some(limit) = options.limit {
+                    upfs.retain(|u| existing_download(&dir, &names, u, options.previews).is_none());
+                    upfs.sort_by_key(|u| u.capture_date);
+                    if !options.oldest {
+                        upfs.reverse();
+                    }
+                    upfs.truncate(limit);
+                }
+                if options.previews {
+                    download_previews(&upfs, &dir, &names, jobs, retries, options.max_rate)?;
+                } else {
+                    download_upfs(&upfs, &dir, &names, jobs, retries, options.max_rate, options.dry_run)?;
+                }
+                if !options.dry_run {
+                    status!("complete");
+                }
+                Ok(CommandStatus::Done)
+            }),
+        },
+    ));
+
+    let dir = output_dir.clone();
+    let names = name_template.clone();
+    let c = client.clone();
+    commands.push((
+        "download_id",
+        command! {
+            "Download a single UPF by ID",
+            (id: String) => |image_id: String| {
+                let res = c.lock().unwrap().get_upf_infos()?;
+                let upf = res
+                    .upf_infos
+                    .iter()
+                    .find(|u| u.image_id == image_id)
+                    .with_context(|| format!("No UPF with ID {image_id} on the camera"))?;
+                download_upfs(&[upf], &dir.borrow(), &names, jobs, retries, None, false)?;
+                status!("complete");
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let dir = output_dir.clone();
+    let names = name_template.clone();
+    let c = client.clone();
+    commands.push((
+        "preview",
+        command! {
+            "Download the preview JPEG of a UPF by ID",
+            (id: String) => |image_id: String| {
+                let res = c.lock().unwrap().get_upf_infos()?;
+                let upf = res
+                    .upf_infos
+                    .iter()
+                    .find(|u| u.image_id == image_id)
+                    .with_context(|| format!("No UPF with ID {image_id} on the camera"))?;
+                download_previews(&[upf], &dir.borrow(), &names, jobs, retries, None)?;
+                status!("complete");
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "download_id",
+        command! {
+            "Download a single UPF by ID to the given path, or to stdout with -",
+            (id: String, path: String) => |image_id: String, path: String| {
+                let res = c.lock().unwrap().get_upf_infos()?;
+                let upf = res
+                    .upf_infos
+                    .iter()
+                    .find(|u| u.image_id == image_id)
+                    .with_context(|| format!("No UPF with ID {image_id} on the camera"))?;
+                if path == "-" {
+                    return download_to_writer(&upf.upf_url, Some(upf.size), &mut std::io::stdout().lock())
+                        .map(|_| CommandStatus::Done);
+                }
+                let download = Download {
+                    image_id,
+                    url: upf.upf_url.clone(),
+                    size: Some(upf.size),
+                    path: expand_home(&path),
+                    detect_image_type: false,
+                };
+                for (download, res) in download_all(vec![download], 1, retries, None) {
+                    res?;
+                    status!("saved to {}", download.path.display());
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let dir = output_dir.clone();
+    let names = name_template.clone();
+    let c = client.clone();
+    commands.push((
+        "get_upf_infos",
+        Command {
+            description: "List UPFs sorted by date, size or id, optionally only those download would fetch (--new-only) or captured within a date range, as a table or CSV".into(),
+            args_info: [
+                "[--sort date|size|id]",
+                "[--new-only]",
+                "[--since <date>]",
+                "[--until <date>]",
+                "[--format csv]",
+            ]
+            .map(String::from)
+            .to_vec(),
+            handler: Box::new(move |args| {
+                let options = parse_list_options(args)?;
+                let mut res = c.lock().unwrap().get_upf_infos()?;
+                warn_if_full(&res);
+                options.dates.retain(&mut res.upf_infos);
+                if options.new_only {
+                    res.upf_infos
+                        .retain(|u| existing_download(&dir.borrow(), &names, u, false).is_none());
+                }
+                match output.json {
+                    _ if options.csv => print!("{}", upf_csv(&res.upf_infos, options.sort)),
+                    true if options.new_only => output.print(&res.upf_infos)?,
+                    true => output.print(&res)?,
+                    false => print_upf_table(&res.upf_infos, options.sort),
+                }
+                Ok(CommandStatus::Done)
+            }),
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "get_status",
+        command! {
+            "Get device status",
+            () => || {
+                let res = c.lock().unwrap().status()?;
+                output.print(&res)?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let cams = cameras.clone();
+    commands.push((
+        "get_status",
+        command! {
+            "Get the status of every connected camera (pass all)",
+            (all: String) => |all: String| {
+                if all != "all" {
+                    bail!("Expected all, got {all:?}");
+                }
+                cams.borrow().for_each(|number, address, client| {
+                    println!("camera {number} at {address}:");
+                    output.print(&client.status()?)
+                })?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let cams = cameras.clone();
+    commands.push((
+        "cameras",
+        command! {
+            "List the connected cameras, marking the one in use with *",
+            () => || {
+                let cams = cams.borrow();
+                for (number, address) in cams.list() {
+                    let marker = if number == cams.active_number() { '*' } else { ' ' };
+                    println!("{marker} {number}: {address}");
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let cams = cameras.clone();
+    commands.push((
+        "use",
+        command! {
+            "Switch the camera commands are sent to, numbered as in cameras",
+            (n: usize) => |number| {
+                cams.borrow_mut().switch(number)?;
+                let token = cams.borrow().active().lock().unwrap().auth_token().map(String::from);
+                download::set_auth_token(token);
+                status!("using camera {number}");
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let cams = cameras.clone();
+    let conn = connector.clone();
+    commands.push((
+        "connect",
+        command! {
+            "Connect to another camera, switch to it with use",
+            (address: String) => |address: String| {
+                let (client, _) = conn.open(&address)?;
+                let number = cams.borrow_mut().add(client, address.clone());
+                status!("connected to camera {number} at {address}");
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "forget_token",
+        command! {
+            "Delete the saved auth token for this camera so the next connection authenticates afresh",
+            () => || {
+                let device_id = c.lock().unwrap().status()?.device_id;
+                let mut tokens = TokenStore::load()?;
+                if tokens.remove(&device_id) {
+                    tokens.save()?;
+                    status!("forgot the saved auth token for {device_id}");
+                } else {
+                    status!("no auth token saved for {device_id}");
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "auth_status",
+        command! {
+            "Check the session is still authenticated without authenticating again",
+            () => || {
+                let res = c.lock().unwrap().status()?;
+                if output.json {
+                    output.print(&serde_json::json!({
+                        "is_auth": res.is_auth,
+                        "capture_available": res.capture_available,
+                    }))?;
+                } else {
+                    println!("authenticated:     {}", res.is_auth);
+                    println!("capture available: {}", res.capture_available);
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "info",
+        command! {
+            "Show which camera this is",
+            () => || {
+                let res = c.lock().unwrap().status()?;
+                if output.json {
+                    output.print(&serde_json::json!({
+                        "device_id": res.device_id,
+                        "serial_number": res.serial_number,
+                        "firmware_version": res.firmware_version,
+                        "current_time": format_timestamp(&res.current_time),
+                    }))?;
+                } else {
+                    println!("device id:        {}", res.device_id);
+                    println!("serial number:    {}", res.serial_number);
+                    println!("firmware version: {}", res.firmware_version);
+                    println!("current time:     {}", format_timestamp(&res.current_time));
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "ping",
+        command! {
+            "Measure the round trip to the camera over 4 status requests",
+            () => || {
+                print_latency(ping(&c, 4, Duration::from_secs(1))?, output)?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "ping",
+        command! {
+            "Measure the round trip to the camera over this many status requests",
+            (count: usize) => |count: usize| {
+                if count == 0 {
+                    bail!("Expected at least one request");
+                }
+                print_latency(ping(&c, count, Duration::from_secs(1))?, output)?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "battery",
+        command! {
+            "Get battery level and charging state",
+            () => || {
+                let res = c.lock().unwrap().status()?;
+                if output.json {
+                    output.print(&serde_json::json!({
+                        "battery_level": res.battery_level,
+                        "is_charging": res.is_charging,
+                    }))?;
+                } else {
+                    match res.battery_level {
+                        Some(level) => println!("battery: {level}%"),
+                        None => println!("battery: unknown (not reported by this firmware)"),
+                    }
+                    if let Some(charging) = res.is_charging {
+                        println!("charging: {charging}");
+                    }
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
 
-            match res {
-                PacketIncoming::Response(r) if r.id == id => {
-                    let text = r.result.get();
-                    return serde_json::from_str::<T>(text)
-                        .with_context(|| format!("Error parsing response {}", &text));
+    let c = client.clone();
+    commands.push((
+        "storage",
+        command! {
+            "Show storage usage",
+            () => || {
+                let res = c.lock().unwrap().status()?;
+                if output.json {
+                    return output.print(&res.storage).map(|_| CommandStatus::Done);
                 }
-                other => {
-                    println!("unexpected packet {:#?}", other);
+                let mut storage = res.storage.iter().collect::<Vec<_>>();
+                storage.sort_by_key(|(name, _)| *name);
+                for (name, s) in storage {
+                    let percent = if s.total > 0 { s.usage as f64 / s.total as f64 * 100. } else { 0. };
+                    println!(
+                        "{name}: {} / {} used ({percent:.1}%), {} free",
+                        HumanBytes(s.usage),
+                        HumanBytes(s.total),
+                        HumanBytes(s.total.saturating_sub(s.usage)),
+                    );
                 }
+                Ok(CommandStatus::Done)
             }
-        }
-    }
-}
-
-fn recv(client: &mut Client<TcpStream>) -> Result<String> {
-    match client.recv_message()? {
-        OwnedMessage::Text(text) => Ok(text),
-        OwnedMessage::Close(_) => bail!("Websocket closed"),
-        OwnedMessage::Binary(_) => unimplemented!(),
-        OwnedMessage::Ping(_) => unimplemented!(),
-        OwnedMessage::Pong(_) => unimplemented!(),
-    }
-}
+        },
+    ));
 
-#[cfg(feature = "ssdp")]
-#[tokio::main(flavor = "current_thread")]
-async fn find_camera() -> Result<String> {
-    use cotton_ssdp::{AsyncService, Notification};
-    use futures::StreamExt;
-    let mut netif = cotton_netif::get_interfaces_async()?;
-    let mut ssdp = AsyncService::new()?;
+    let c = client.clone();
+    commands.push((
+        "firmware",
+        command! {
+            "Show firmware version and whether an update is ready",
+            () => || {
+                let res = c.lock().unwrap().status()?;
+                if output.json {
+                    output.print(&serde_json::json!({
+                        "firmware_version": res.firmware_version,
+                        "update_ready": res.update_ready,
+                        "firmware_update_url": res.firmware_update_url,
+                    }))?;
+                } else {
+                    println!("firmware version: {}", res.firmware_version);
+                    println!("update ready:     {}", res.update_ready);
+                    if !res.firmware_update_url.is_empty() {
+                        println!("update url:       {}", res.firmware_update_url);
+                        println!("use firmware_download <path> to save it");
+                    }
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
 
-    let mut stream = ssdp.subscribe("panono:ball-camera");
-    println!("Searching for camera...");
-    let location = loop {
-        tokio::select! {
-            notification = stream.next() => {
-                if let Some(Notification::Alive { location, .. }) = notification {
-                    break location;
+    let c = client.clone();
+    commands.push((
+        "firmware_download",
+        command! {
+            "Download the firmware update offered by the camera",
+            (path: String) => |path: String| {
+                let res = c.lock().unwrap().status()?;
+                if res.firmware_update_url.is_empty() {
+                    bail!("The camera does not offer a firmware update");
                 }
-            },
-            e = netif.next() => {
-                if let Some(Ok(event)) = e {
-                    ssdp.on_network_event(&event);
+                let download = Download {
+                    image_id: "firmware".to_string(),
+                    url: res.firmware_update_url,
+                    size: None,
+                    path: expand_home(&path),
+                    detect_image_type: false,
+                };
+                for (download, res) in download_all(vec![download], 1, retries, None) {
+                    res?;
+                    status!("saved firmware to {}", download.path.display());
                 }
+                Ok(CommandStatus::Done)
             }
-        }
-    };
-    println!("Camera found at {location}");
-    Ok(location)
-}
+        },
+    ));
 
-/// 3rd party REPL for Panono 360 Camera
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Websocket address for the camera. If ommitted, it attempt to locate it with SSDP
-    /// Example on WiFi: ws://192.168.80.80:12345/8086
-    address: Option<String>,
-}
+    let c = client.clone();
+    commands.push((
+        "get_options",
+        command! {
+            "Get options",
+            () => || {
+                let res = c.lock().unwrap().get_options()?;
+                output.print(&res)?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+    let c = client.clone();
+    commands.push((
+        "get_option_list",
+        command! {
+            "Get option list",
+            () => || {
+                let res = c.lock().unwrap().get_option_list()?;
+                output.print(&res)?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
 
-    let address = match args.address {
-        Some(address) => {
-            println!("Connecting to {}", address);
-            address
-        }
-        None => {
-            #[cfg(feature = "ssdp")]
-            {
-                find_camera()?
+    let c = client.clone();
+    commands.push((
+        "get_option_list",
+        command! {
+            "Get the options of one type: Boolean, Enumeration, Number or Integer",
+            (kind: String) => |kind: String| {
+                let mut res = c.lock().unwrap().get_option_list()?;
+                res.retain_type(&kind)?;
+                output.print(&res)?;
+                Ok(CommandStatus::Done)
             }
-            #[cfg(not(feature = "ssdp"))]
-            {
-                bail!("Automatic discovery (\"ssdp\" feature, Linux only) is disabled. See --help to specify manually")
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "get_option_value",
+        command! {
+            "Get option value",
+            (name: KnownOption) => |name| {
+                let res = c.lock().unwrap().get_option(name)?;
+                output.print(&res)?;
+                Ok(CommandStatus::Done)
             }
-        }
-    };
+        },
+    ));
 
-    let output_dir = Path::new("upfs");
+    let c = client.clone();
+    commands.push((
+        "set_option",
+        command! {
+            "Set option value",
+            (name: KnownOption, value: String) => |name: KnownOption, value: String| {
+                let res = set_option(&mut c.lock().unwrap(), name, &value)?;
+                output.print(&res)?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "set_options",
+        Command {
+            description: "Set several options at once, e.g. set_options iso=400 auto_exposure=false color_temp=5500".into(),
+            args_info: vec!["<name=value>...".into()],
+            handler: Box::new(move |args| {
+                set_options(&mut c.lock().unwrap(), args)?;
+                Ok(CommandStatus::Done)
+            }),
+        },
+    ));
 
-    let client = Rc::new(RefCell::new(
-        ClientBuilder::new(&address)
-            .unwrap()
-            .add_protocol("rust-websocket")
-            .connect_insecure()?,
+    let c = client.clone();
+    commands.push((
+        "adjust_option",
+        command! {
+            "Set an option to the next (+) or previous (-) of its allowed values",
+            (name: KnownOption, direction: String) => |name: KnownOption, direction: String| {
+                let up = match direction.as_str() {
+                    "+" => true,
+                    "-" => false,
+                    _ => bail!("Expected + or -, got {direction:?}"),
+                };
+                let mut client = c.lock().unwrap();
+                let list = client.get_option_list()?;
+                let option = list
+                    .options
+                    .iter()
+                    .find(|o| o.name() == name.as_str())
+                    .with_context(|| format!("Unknown option {name}"))?;
+                let current = client.get_option(name.clone())?.value;
+                let value = option.step_value(&current, up)?;
+                let res = client.set_option(name.clone(), value.clone())?;
+                if output.json {
+                    output.print(&res)?;
+                } else {
+                    println!("{name}: {current} -> {value}");
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
     ));
 
-    let mut req_id = 0;
+    let c = client.clone();
+    commands.push((
+        "last_raw",
+        command! {
+            "Print the raw JSON of the last response from the camera",
+            () => || {
+                let client = c.lock().unwrap();
+                let Some(text) = client.last_response() else {
+                    bail!("No response received yet");
+                };
+                match serde_json::from_str::<serde_json::Value>(text) {
+                    Ok(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+                    Err(_) => println!("{text}"),
+                }
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
 
-    let auth: ResponseStatus = send(
-        &mut client.borrow_mut(),
-        &mut req_id,
-        Method::Auth {
-            device: "test".to_string(),
-            force: "test".to_string(),
+    let c = client.clone();
+    commands.push((
+        "export_config",
+        command! {
+            "Save every option with its type, constraints and current value to a JSON file",
+            (path: String) => |path: String| {
+                let path = expand_home(&path);
+                let count = export_options(&mut c.lock().unwrap(), &path)?;
+                status!("saved {count} options to {}", path.display());
+                Ok(CommandStatus::Done)
+            }
         },
-    )?;
-    println!("{:#?}", auth);
+    ));
 
-    use easy_repl::{command, CommandStatus, Repl};
+    let c = client.clone();
+    commands.push((
+        "import_config",
+        command! {
+            "Restore option values saved by export_config",
+            (path: String) => |path: String| {
+                import_options(&mut c.lock().unwrap(), &expand_home(&path))?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
 
-    let repl = Repl::builder();
+    let c = client.clone();
+    commands.push((
+        "set_color_temp",
+        command! {
+            "Set color temperature in kelvin (0 for auto)",
+            (kelvin: String) => |kelvin: String| {
+                let res = set_option(&mut c.lock().unwrap(), KnownOption::ColorTemperature, &kelvin)?;
+                output.print(&res)?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
 
     let c = client.clone();
-    let repl = repl.add(
-        "delete",
+    commands.push((
+        "capture",
         command! {
-            "Delete UPF by ID",
-            (id: String) => |image_id| {
-                let res: ResponseDelete = send(&mut c.borrow_mut(), &mut req_id, Method::DeleteUpf{ image_id })?;
-                println!("{:#?}", res);
+            "Capture new panorama",
+            () => || {
+                let mut client = c.lock().unwrap();
+                ensure_capture_available(&client)?;
+                let res = capture(&mut client, None)?;
+                output.print(&res)?;
                 Ok(CommandStatus::Done)
             }
         },
-    );
+    ));
 
     let c = client.clone();
-    let repl = repl.add(
-        "download",
+    commands.push((
+        "capture",
+        Command {
+            description: "Capture new panorama with some settings overridden".into(),
+            args_info: [
+                "[--iso <iso>]",
+                "[--exposure <seconds|1/n>]",
+                "[--trigger-delay <seconds>]",
+                "[--color-temp <kelvin|auto>]",
+                "[--auto-exposure <true|false>]",
+            ]
+            .map(String::from)
+            .to_vec(),
+            handler: Box::new(move |args| {
+                let options = parse_capture_options(args)?;
+                let mut client = c.lock().unwrap();
+                ensure_capture_available(&client)?;
+                let res = capture(&mut client, Some(options))?;
+                output.print(&res)?;
+                Ok(CommandStatus::Done)
+            }),
+        },
+    ));
+
+    let poll_interval = Duration::from_secs(args.poll_interval);
+    let capture_timeout = Duration::from_secs(args.capture_timeout);
+    let c = client.clone();
+    commands.push((
+        "wait_ready",
         command! {
-            "Download any new UPFs",
+            "Wait until the camera is ready to capture, giving up after --capture-timeout seconds",
             () => || {
-                let res: ResponseGetUpfInfos = send(&mut c.borrow_mut(), &mut req_id, Method::GetUpfInfos)?;
-                let mut to_download = vec![];
-                for upf in &res.upf_infos {
-                    let path = output_dir.join(&format!("{}.upf", upf.image_id));
-                    if path.exists() {
-                        println!("{} already exists, skipping...", path.display());
-                    } else {
-                        to_download.push((upf, path));
-                    }
-                }
-                fs::create_dir(output_dir).ok();
-                for (i, (upf, path)) in to_download.iter().enumerate() {
-                    println!("[{}/{}] downloading {} to {}", i + 1, to_download.len(), upf.image_id, path.display());
-                    let res = ureq::get(&upf.upf_url)
-                        .call()?;
-                    let size = res.header("Content-Length").and_then(|l| l.parse::<usize>().ok()).unwrap_or(upf.size as usize);
-                    let pb = ProgressBar::new(size as u64).with_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                        .unwrap()
-                        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-                        .progress_chars("#>-"));
-                    let mut data = Vec::with_capacity(size);
-                    for b in res.into_reader().bytes().progress_with(pb) {
-                        data.push(b?);
-                    }
-                    fs::write(path, data)?;
-                }
-                println!("complete");
+                wait_ready(&c, poll_interval, capture_timeout)?;
+                status!("ready");
                 Ok(CommandStatus::Done)
             }
         },
-    );
+    ));
 
     let c = client.clone();
-    let repl = repl.add(
-        "get_upf_infos",
+    commands.push((
+        "wait_ready",
         command! {
-            "List all UPFs",
-            () =>
-            || {
-                let res: ResponseGetUpfInfos = send(&mut c.borrow_mut(), &mut req_id, Method::GetUpfInfos)?;
-                let mut upfs = res.upf_infos.iter().collect::<Vec<_>>();
-                upfs.sort_by_key(|u| &u.capture_date);
-                for upf in upfs {
-                    println!("{}  {}  {:>7}  {}", upf.capture_date, upf.image_id, upf.size, upf.upf_url);
-                }
+            "Wait until the camera is ready to capture, giving up after the given number of seconds",
+            (timeout: u64) => |timeout| {
+                wait_ready(&c, poll_interval, Duration::from_secs(timeout))?;
+                status!("ready");
                 Ok(CommandStatus::Done)
             }
         },
-    );
+    ));
 
+    let dir = output_dir.clone();
+    let names = name_template.clone();
     let c = client.clone();
-    let repl = repl.add(
-        "get_status",
+    commands.push((
+        "capture_and_download",
         command! {
-            "Get device status",
+            "Capture a new panorama, wait for it to be ready, and download it",
             () => || {
-                let res: ResponseStatus = send(&mut c.borrow_mut(), &mut req_id, Method::GetStatus)?;
-                println!("{:#?}", res);
+                let known = {
+                    let mut client = c.lock().unwrap();
+                    ensure_capture_available(&client)?;
+                    let known = client
+                        .get_upf_infos()?
+                        .upf_infos
+                        .into_iter()
+                        .map(|u| u.image_id)
+                        .collect::<HashSet<_>>();
+                    let res = capture(&mut client, None)?;
+                    output.print(&res)?;
+                    known
+                };
+                status!("waiting for the new UPF...");
+                let upfs = wait_for_new_upfs(&c, &known, poll_interval, capture_timeout)?;
+                download_upfs(&upfs.iter().collect::<Vec<_>>(), &dir.borrow(), &names, jobs, retries, None, false)?;
+                status!("complete");
                 Ok(CommandStatus::Done)
             }
         },
-    );
+    ));
 
     let c = client.clone();
-    let repl = repl.add(
-        "get_options",
+    commands.push((
+        "watch",
         command! {
-            "Get options",
+            "Print new UPFs as they appear on the camera until Ctrl-C",
             () => || {
-                let res: ResponseStatus = send(&mut c.borrow_mut(), &mut req_id, Method::GetOptions)?;
-                println!("{:#?}", res);
+                watch(&c, poll_interval, |_| Ok(()))?;
                 Ok(CommandStatus::Done)
             }
         },
-    );
+    ));
 
+    let dir = output_dir.clone();
+    let names = name_template.clone();
     let c = client.clone();
-    let repl = repl.add(
-        "get_option_list",
+    commands.push((
+        "watch",
         command! {
-            "Get option list",
+            "Download new UPFs as they appear on the camera until Ctrl-C (pass --download)",
+            (download: String) => |download: String| {
+                if download != "--download" {
+                    bail!("Expected --download, got {download:?}");
+                }
+                watch(&c, poll_interval, |upfs| {
+                    // keep watching if a download fails
+                    if let Err(e) = download_upfs(&upfs.iter().collect::<Vec<_>>(), &dir.borrow(), &names, jobs, retries, None, false) {
+                        if !events::enabled() {
+                            println!("{e:#}");
+                        }
+                    }
+                    Ok(())
+                })?;
+                Ok(CommandStatus::Done)
+            }
+        },
+    ));
+
+    let c = client.clone();
+    commands.push((
+        "open",
+        command! {
+            "Open the camera's web interface in a browser",
             () => || {
-                let res: ResponseGetOptionList = send(&mut c.borrow_mut(), &mut req_id, Method::GetOptionList)?;
-                println!("{:#?}", res);
+                let url = c.lock().unwrap().web_ui_url()?;
+                status!("opening {url}");
+                open_in_browser(&url)?;
                 Ok(CommandStatus::Done)
             }
         },
-    );
+    ));
 
     let c = client.clone();
-    let repl = repl.add(
-        "get_option_value",
+    commands.push((
+        "raw",
         command! {
-            "Get option value",
-            (name: String) => |name| {
-                let res: ResponseGetOption = send(&mut c.borrow_mut(), &mut req_id, Method::GetOption { name })?;
-                println!("{:#?}", res);
+            "Call any JSON-RPC method without params and print the raw result",
+            (method: String) => |method: String| {
+                let res = c.lock().unwrap().send_raw(&method, None)?;
+                println!("{}", res.get());
                 Ok(CommandStatus::Done)
             }
         },
-    );
+    ));
 
     let c = client.clone();
-    let repl = repl.add(
-        "capture",
+    commands.push((
+        "raw",
         command! {
-            "Capture new panorama",
-            () => || {
-                let res: ResponseCapture = send(&mut c.borrow_mut(), &mut req_id, Method::Capture)?;
-                println!("{:#?}", res);
+            "Call any JSON-RPC method with JSON params and print the raw result",
+            (method: String, params: String) => |method: String, params: String| {
+                let params: &serde_json::value::RawValue =
+                    serde_json::from_str(&params).context("Invalid JSON params")?;
+                let res = c.lock().unwrap().send_raw(&method, Some(params))?;
+                println!("{}", res.get());
                 Ok(CommandStatus::Done)
             }
         },
-    );
+    ));
 
-    let mut repl = repl.build().expect("Failed to create repl");
+    let c = client.clone();
+    let mut repl = Repl::new(move || {
+        let list = c.lock().unwrap().get_option_list()?;
+        Ok(list.options.iter().map(|o| o.name().to_string()).collect())
+    });
+    for (name, command) in commands {
+        repl.add(name, command);
+    }
+    for (name, expansion) in DEFAULT_ALIASES {
+        repl.add_alias(name, expansion)?;
+    }
+    for alias in &args.aliases {
+        let (name, expansion) = alias
+            .split_once('=')
+            .with_context(|| format!("Expected --alias name=command, got {alias:?}"))?;
+        repl.add_alias(name, expansion)?;
+    }
+    // once the connection is gone for good every command would fail the same way, unless there are
+    // other cameras to switch to
+    let cams = cameras.clone();
+    repl.set_is_fatal(move |e| e.is::<Disconnected>() && cams.borrow().len() == 1);
+    repl.set_history_file(dirs::data_dir().map(|dir| dir.join("panonoctl").join("history")));
+    let c = client.clone();
+    let cams = cameras.clone();
+    repl.set_prompt(move || {
+        let client = c.lock().unwrap();
+        let state = [
+            client
+                .capture_available()
+                .map(|ready| if ready { "ready" } else { "busy" }.to_string()),
+            client.battery_level().map(|level| format!("{level}%")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        drop(client);
+        let state = if state.is_empty() {
+            String::new()
+        } else {
+            format!("[{}]", state.join(" "))
+        };
+        // only worth pointing out which camera is in use once there is a choice
+        let cams = cams.borrow();
+        match cams.len() {
+            1 => format!("panono{state}> "),
+            _ => format!("panono{}{state}> ", cams.active_number()),
+        }
+    });
 
-    repl.run().expect("Critical REPL error");
+    let res = match &args.command {
+        Some(name) => {
+            let args = args.args.iter().map(String::as_str).collect::<Vec<_>>();
+            repl.run_command(name, &args).map(|_| ())
+        }
+        None => repl.run(),
+    };
+    if let Err(e) = &res {
+        events::emit(&Event::Error {
+            image_id: None,
+            message: format!("{e:#}"),
+        });
+    }
 
-    Ok(())
+    cameras.borrow().close_all();
+    res
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use panonoctl_rs::mock::{MockCamera, Reply};
 
     #[test]
-    fn options() {
-        serde_json::from_str::<ResponseGetOptionList>(
-            r#"{
-            "options": [{
-                    "constraints": [{
-                        "constraint": "values",
-                        "value": [true, false]
-                    }],
-                    "name": "AutoExposure",
-                    "type": "Boolean"
-                },
-                {
-                    "constraints": [{
-                        "constraint": "values",
-                        "value": ["0", "3000", "4500", "5500", "6500", "8000"]
-                    }],
-                    "name": "ColorTemperature",
-                    "type": "Enumeration"
-                },
-                {
-                    "constraints": [{
-                            "constraint": "min",
-                            "value": "0.25"
-                        },
-                        {
-                            "constraint": "max",
-                            "value": "2000"
-                        }
-                    ],
-                    "name": "ExposureTime",
-                    "type": "Number"
-                },
-                {
-                    "constraints": [{
-                        "constraint": "values",
-                        "value": ["50", "100", "200", "400", "800"]
-                    }],
-                    "name": "ISO",
-                    "type": "Enumeration"
-                },
-                {
-                    "constraints": [{
-                            "constraint": "min",
-                            "value": 0
-                        },
-                        {
-                            "constraint": "max",
-                            "value": 10000
-                        }
-                    ],
-                    "name": "TriggerDelay",
-                    "type": "Integer"
-                }
-            ]
-        }"#,
-        )
+    fn expand_home_dir() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_home("~/upfs"), home.join("upfs"));
+        assert_eq!(expand_home("~"), home);
+        assert_eq!(expand_home("~other/upfs"), PathBuf::from("~other/upfs"));
+        assert_eq!(expand_home("upfs"), PathBuf::from("upfs"));
+    }
+
+    #[test]
+    fn capture_options() {
+        let options = parse_capture_options(&[
+            "--iso",
+            "400",
+            "--exposure",
+            "1/100",
+            "--trigger-delay",
+            "2",
+        ])
         .unwrap();
+        assert_eq!(options.iso.as_deref(), Some("400"));
+        assert_eq!(options.exposure_time, Some(0.01));
+        assert_eq!(options.trigger_delay, Some(2.));
+        assert_eq!(options.auto_exposure, None);
+        assert!(parse_capture_options(&["--iso"]).is_err());
+        assert!(parse_capture_options(&["--shutter", "1"]).is_err());
+    }
+
+    #[test]
+    fn download_options() {
+        assert_eq!(
+            parse_download_options(&[]).unwrap(),
+            DownloadOptions::default()
+        );
+        assert_eq!(
+            parse_download_options(&["--previews", "--max-rate", "1000000"]).unwrap(),
+            DownloadOptions {
+                previews: true,
+                max_rate: Some(1_000_000),
+                ..Default::default()
+            }
+        );
+        assert!(parse_download_options(&["--max-rate"]).is_err());
+        assert!(parse_download_options(&["--max-rate", "0"]).is_err());
+        assert!(parse_download_options(&["--dry-run", "--previews"]).is_err());
+        assert_eq!(
+            parse_download_options(&["--limit", "5", "--oldest"]).unwrap(),
+            DownloadOptions {
+                limit: Some(5),
+                oldest: true,
+                ..Default::default()
+            }
+        );
+        assert!(parse_download_options(&["--limit", "-1"]).is_err());
+    }
+
+    #[test]
+    fn list_options() {
+        let options = parse_list_options(&["size", "--since", "2017-06-03", "--new-only"]).unwrap();
+        assert_eq!(options.sort, UpfSort::Size);
+        assert!(options.new_only);
+        assert_eq!(
+            options.dates.since,
+            Some("2017-06-03T00:00:00Z".parse().unwrap())
+        );
+        assert!(parse_list_options(&["--until"]).is_err());
+        assert!(parse_list_options(&["--since", "June"]).is_err());
+        assert!(parse_list_options(&["newest"]).is_err());
+        let options = parse_list_options(&["--sort", "id", "--new-only"]).unwrap();
+        assert_eq!(options.sort, UpfSort::Id);
+        assert!(options.new_only);
+        assert!(parse_list_options(&["--sort"]).is_err());
+        assert!(parse_list_options(&["--sort", "newest"]).is_err());
+        assert!(parse_list_options(&["--format", "csv"]).unwrap().csv);
+        assert!(parse_list_options(&["--format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let upf = |id: &str, size, date: &str| UpfInfo {
+            capture_date: date.parse().unwrap(),
+            image_id: id.into(),
+            preview_url: format!("http://192.168.80.80/previews/{id}.jpg"),
+            size,
+            upf_url: format!("http://192.168.80.80/upfs/{id}.upf"),
+        };
+        let upfs = [
+            upf("b", 200, "2017-06-03T14:20:51Z"),
+            upf("a,\"1\"", 100, "2017-06-01T09:00:00Z"),
+        ];
+        assert_eq!(
+            upf_csv(&upfs, UpfSort::Date),
+            "capture_date,image_id,size,upf_url,preview_url\n\
+             2017-06-01T09:00:00Z,\"a,\"\"1\"\"\",100,\"http://192.168.80.80/upfs/a,\"\"1\"\".upf\",\"http://192.168.80.80/previews/a,\"\"1\"\".jpg\"\n\
+             2017-06-03T14:20:51Z,b,200,http://192.168.80.80/upfs/b.upf,http://192.168.80.80/previews/b.jpg\n"
+        );
+    }
+
+    #[test]
+    fn date_range() {
+        let upf = |capture_date: &str| UpfInfo {
+            capture_date: DateTime::parse_from_rfc3339(capture_date).unwrap(),
+            image_id: capture_date.into(),
+            preview_url: String::new(),
+            size: 0,
+            upf_url: String::new(),
+        };
+        let mut upfs = vec![
+            upf("2017-06-02T23:59:59Z"),
+            upf("2017-06-03T14:20:51Z"),
+            upf("2017-06-04T08:00:00+02:00"),
+            upf("2017-06-04T08:00:00Z"),
+        ];
+        let mut dates = DateRange::default();
+        dates.set("--since", "2017-06-03").unwrap();
+        dates.set("--until", "2017-06-04T07:00:00Z").unwrap();
+        dates.retain(&mut upfs);
+        let ids = upfs.iter().map(|u| u.image_id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["2017-06-03T14:20:51Z", "2017-06-04T08:00:00+02:00"]);
+    }
+
+    #[test]
+    fn diff() {
+        let dir = std::env::temp_dir().join(format!("panonoctl-diff-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let names = "{capture_date}_{short_id}".parse::<NameTemplate>().unwrap();
+        let upf = |image_id: &str| UpfInfo {
+            capture_date: DateTime::parse_from_rfc3339("2017-06-03T14:20:51Z").unwrap(),
+            image_id: image_id.into(),
+            preview_url: String::new(),
+            size: 0,
+            upf_url: String::new(),
+        };
+
+        // one download named by the template, one from before the manifest, one since deleted
+        let mut manifest = Manifest::default();
+        for id in ["aaaaaaaa11", "cccccccc33"] {
+            manifest.insert(ManifestEntry::new(&upf(id), String::new()));
+        }
+        manifest.save(&dir).unwrap();
+        fs::write(dir.join("2017-06-03_142051_aaaaaaaa.upf"), "").unwrap();
+        fs::write(dir.join("bbbbbbbb22.upf"), "").unwrap();
+
+        let local = local_upfs(&dir, &names).unwrap();
+        assert_eq!(
+            local.keys().collect::<Vec<_>>(),
+            ["aaaaaaaa11", "bbbbbbbb22"]
+        );
+        let camera = [upf("aaaaaaaa11"), upf("dddddddd44")];
+        let diff = diff_upfs(&camera, local);
+        assert_eq!(diff.camera_only.len(), 1);
+        assert_eq!(diff.camera_only[0].image_id, "dddddddd44");
+        assert_eq!(
+            diff.local_only,
+            BTreeMap::from([("bbbbbbbb22".into(), dir.join("bbbbbbbb22.upf"))])
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn set_option_checked() {
+        let camera = MockCamera::start().unwrap();
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+        assert!(set_option(&mut client, KnownOption::Iso, "123").is_err());
+        assert!(set_option(&mut client, "Shutter".parse().unwrap(), "1").is_err());
+        set_option(&mut client, "iso".parse().unwrap(), "400").unwrap();
+
+        // only the valid value was sent on to the camera
+        let sent = camera
+            .requests()
+            .into_iter()
+            .filter(|r| r["method"] == "set_option")
+            .map(|r| r["params"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(sent, [serde_json::json!({"name": "ISO", "value": "400"})]);
     }
 
     #[test]
-    fn constraint() {
-        serde_json::from_str::<CameraOption>(
-            r#"{
-            "constraints": [{
-                "constraint": "values",
-                "value": [true, false]
-            }],
-            "name": "AutoExposure",
-            "type": "Boolean"
-        }"#,
+    fn set_several_options() {
+        let camera = MockCamera::start().unwrap();
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+        let sent = || {
+            camera
+                .requests()
+                .into_iter()
+                .filter(|r| r["method"] == "set_option")
+                .map(|r| r["params"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        // one bad value stops all of them
+        for bad in [&["iso=400", "color_temp=1234"][..], &["iso"], &[]] {
+            assert!(set_options(&mut client, bad).is_err());
+        }
+        assert!(sent().is_empty());
+
+        set_options(
+            &mut client,
+            &["iso=400", "auto_exposure=false", "exposure=0.5"],
         )
         .unwrap();
+        assert_eq!(
+            sent(),
+            [
+                serde_json::json!({"name": "ISO", "value": "400"}),
+                serde_json::json!({"name": "AutoExposure", "value": false}),
+                serde_json::json!({"name": "ExposureTime", "value": 0.5}),
+            ]
+        );
+    }
+
+    #[test]
+    fn latency() {
+        let camera = MockCamera::start().unwrap();
+        let client = Mutex::new(PanonoClient::connect(camera.address(), false).unwrap());
+        let latency = ping(&client, 3, Duration::ZERO).unwrap().unwrap();
+        assert_eq!(latency.replies, 3);
+        assert!(latency.min_ms <= latency.avg_ms && latency.avg_ms <= latency.max_ms);
+        assert_eq!(camera.requests().len(), 3);
+
+        let ms = Duration::from_millis;
+        assert_eq!(
+            Latency::new(&[ms(30), ms(10), ms(20)]),
+            Some(Latency {
+                replies: 3,
+                min_ms: 10.0,
+                avg_ms: 20.0,
+                max_ms: 30.0
+            })
+        );
+        assert_eq!(Latency::new(&[]), None);
+    }
+
+    #[test]
+    fn export_import_options() {
+        let camera = MockCamera::start().unwrap();
+        let mut client = PanonoClient::connect(camera.address(), false).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("panonoctl-options-{}.json", std::process::id()));
+
+        assert_eq!(export_options(&mut client, &path).unwrap(), 5);
+        let mut saved: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved["ISO"]["type"], "Enumeration");
+        assert_eq!(saved["ISO"]["value"], "100");
+        assert_eq!(saved["ExposureTime"]["constraints"][0]["constraint"], "min");
+
+        // only what changed since the export is set again
+        saved["ISO"]["value"] = "400".into();
+        fs::write(&path, saved.to_string()).unwrap();
+        import_options(&mut client, &path).unwrap();
+        let sent = camera
+            .requests()
+            .into_iter()
+            .filter(|r| r["method"] == "set_option")
+            .map(|r| r["params"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(sent, [serde_json::json!({"name": "ISO", "value": "400"})]);
+
+        saved["ISO"]["value"] = "123".into();
+        fs::write(&path, saved.to_string()).unwrap();
+        assert!(import_options(&mut client, &path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn new_upfs() {
+        let camera = MockCamera::start().unwrap();
+        let client = Mutex::new(PanonoClient::connect(camera.address(), false).unwrap());
+        let known = HashSet::from(["4fd70dfc074340296cc2ebb92158a18d".to_string()]);
+        let interval = Duration::from_millis(10);
+        assert!(wait_for_new_upfs(&client, &known, interval, Duration::ZERO).is_err());
+
+        let upf = |id: &str| {
+            serde_json::json!({
+                "capture_date": "2017-06-04T09:00:00Z",
+                "image_id": id,
+                "preview_url": "",
+                "size": 117964800,
+                "upf_url": ""
+            })
+        };
+        camera.reply_once(
+            "get_upf_infos",
+            Reply::Result(serde_json::json!({
+                "is_full": false,
+                "upf_infos": [upf("4fd70dfc074340296cc2ebb92158a18d"), upf("new")]
+            })),
+        );
+        let new = wait_for_new_upfs(&client, &known, interval, Duration::from_secs(5)).unwrap();
+        assert_eq!(new.len(), 1);
+        assert_eq!(new[0].image_id, "new");
     }
 }
@@ -0,0 +1,106 @@
+use anyhow::{Context, Result};
+use panonoctl_rs::{format_timestamp, UpfInfo};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Record of the UPFs downloaded to a directory, kept as `manifest.json` alongside them so an
+/// archive can be checked later
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub upfs: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub image_id: String,
+    pub size: u64,
+    pub capture_date: String,
+    /// Hex encoded SHA-256 of the downloaded file
+    pub sha256: String,
+}
+
+impl ManifestEntry {
+    pub fn new(upf: &UpfInfo, sha256: String) -> Self {
+        Self {
+            image_id: upf.image_id.clone(),
+            size: upf.size,
+            capture_date: format_timestamp(&upf.capture_date),
+            sha256,
+        }
+    }
+}
+
+impl Manifest {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("manifest.json")
+    }
+
+    /// Load the manifest in `dir`, starting a new one if there is none yet
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = Self::path(dir);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = Self::path(dir);
+        // write alongside and rename over so an interrupted save can't lose the existing manifest
+        let tmp = dir.join("manifest.json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Add an entry, replacing any earlier one for the same UPF, keeping entries in capture order
+    pub fn insert(&mut self, entry: ManifestEntry) {
+        self.upfs.retain(|e| e.image_id != entry.image_id);
+        self.upfs.push(entry);
+        self.upfs
+            .sort_by(|a, b| (&a.capture_date, &a.image_id).cmp(&(&b.capture_date, &b.image_id)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(image_id: &str, capture_date: &str, sha256: &str) -> ManifestEntry {
+        ManifestEntry {
+            image_id: image_id.into(),
+            size: 117964800,
+            capture_date: capture_date.into(),
+            sha256: sha256.into(),
+        }
+    }
+
+    #[test]
+    fn incremental() {
+        let dir = std::env::temp_dir().join(format!("panonoctl-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut manifest = Manifest::load(&dir).unwrap();
+        assert!(manifest.upfs.is_empty());
+        manifest.insert(entry("b", "2017-06-03T14:20:51Z", "00"));
+        manifest.save(&dir).unwrap();
+
+        let mut manifest = Manifest::load(&dir).unwrap();
+        manifest.insert(entry("a", "2017-06-04T09:00:00Z", "11"));
+        manifest.insert(entry("b", "2017-06-03T14:20:51Z", "22"));
+        manifest.save(&dir).unwrap();
+
+        assert_eq!(
+            Manifest::load(&dir).unwrap().upfs,
+            [
+                entry("b", "2017-06-03T14:20:51Z", "22"),
+                entry("a", "2017-06-04T09:00:00Z", "11"),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+//! Stand-in for the camera's websocket server, answering each method with a canned response so
+//! the client and commands can be tested without hardware
+
+use crate::JSONRPC_VERSION;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use websocket::{sync::Server, Message, OwnedMessage};
+
+/// How the mock camera answers a method
+#[derive(Debug, Clone)]
+pub enum Reply {
+    Result(Value),
+    Error {
+        code: u32,
+        message: String,
+    },
+    /// Wait this long before sending the result, to exercise timeouts
+    Delayed(Duration, Value),
+    /// Close the connection instead of answering, to exercise reconnecting
+    HangUp,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    replies: HashMap<String, Reply>,
+    /// Used before `replies`, one per request
+    once: HashMap<String, VecDeque<Reply>>,
+    requests: Vec<Value>,
+    connections: usize,
+}
+
+impl State {
+    fn reply(&mut self, method: &str) -> Option<Reply> {
+        self.once
+            .get_mut(method)
+            .and_then(VecDeque::pop_front)
+            .or_else(|| self.replies.get(method).cloned())
+    }
+}
+
+/// A camera listening on a local port. Every method `Method` covers has a plausible default
+/// response which tests can override with `reply` or `reply_once`. The server keeps accepting
+/// connections, one at a time, until the test process exits.
+pub struct MockCamera {
+    address: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockCamera {
+    pub fn start() -> anyhow::Result<Self> {
+        let mut server = Server::bind("127.0.0.1:0")?;
+        let address = format!("ws://{}/8086", server.local_addr()?);
+        let state = Arc::new(Mutex::new(State {
+            replies: default_replies(),
+            ..Default::default()
+        }));
+
+        let s = state.clone();
+        thread::spawn(move || {
+            while let Ok(upgrade) = server.accept() {
+                let Ok(client) = upgrade.use_protocol("rust-websocket").accept() else {
+                    continue;
+                };
+                s.lock().unwrap().connections += 1;
+                serve(client, &s);
+            }
+        });
+        Ok(Self { address, state })
+    }
+
+    /// Address to pass to `PanonoClient::connect`
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Answer every later call to `method` with `reply`
+    pub fn reply(&self, method: &str, reply: Reply) {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .insert(method.to_string(), reply);
+    }
+
+    /// Answer the next call to `method` with `reply`, going back to the usual reply afterwards.
+    /// Queues up if called repeatedly.
+    pub fn reply_once(&self, method: &str, reply: Reply) {
+        self.state
+            .lock()
+            .unwrap()
+            .once
+            .entry(method.to_string())
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Every request received so far, in order
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+}
+
+/// Answer requests on one connection until the client goes away or a reply hangs up
+fn serve(mut client: websocket::sync::Client<std::net::TcpStream>, state: &Mutex<State>) {
+    loop {
+        let text = match client.recv_message() {
+            Ok(OwnedMessage::Text(text)) => text,
+            Ok(OwnedMessage::Ping(data)) => {
+                let _ = client.send_message(&OwnedMessage::Pong(data));
+                continue;
+            }
+            Ok(OwnedMessage::Close(_)) | Err(_) => return,
+            Ok(_) => continue,
+        };
+        let Ok(request) = serde_json::from_str::<Value>(&text) else {
+            return;
+        };
+        let method = request["method"].as_str().unwrap_or_default().to_string();
+        let id = request["id"].clone();
+        let reply = {
+            let mut state = state.lock().unwrap();
+            state.requests.push(request);
+            state.reply(&method)
+        };
+        let response = match reply {
+            Some(Reply::Result(result)) => {
+                json!({"id": id, "jsonrpc": JSONRPC_VERSION, "result": result})
+            }
+            Some(Reply::Delayed(delay, result)) => {
+                thread::sleep(delay);
+                json!({"id": id, "jsonrpc": JSONRPC_VERSION, "result": result})
+            }
+            Some(Reply::Error { code, message }) => {
+                json!({"id": id, "jsonrpc": JSONRPC_VERSION, "error": {"code": code, "message": message}})
+            }
+            Some(Reply::HangUp) => {
+                let _ = client.shutdown();
+                return;
+            }
+            // the camera's answer to methods it doesn't know
+            None => {
+                json!({"id": id, "jsonrpc": JSONRPC_VERSION, "error": {"code": 32601, "message": "Method not found"}})
+            }
+        };
+        if client
+            .send_message(&Message::text(response.to_string()))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn status() -> Value {
+    json!({
+        "auth_token": "b6e5a4c1f0d94c0e",
+        "capture_available": true,
+        "current_time": "2017-06-03T14:21:07Z",
+        "device_id": "a1b2c3d4e5f6",
+        "firmware_update_url": "",
+        "firmware_version": "0.3.2-Apricot.product.412",
+        "is_auth": true,
+        "serial_number": "PN00123456",
+        "storage": {"internal": {"total": 15758000128u64, "usage": 2147483648u64}},
+        "update_ready": false
+    })
+}
+
+fn default_replies() -> HashMap<String, Reply> {
+    let options = json!({
+        "AutoExposure": true,
+        "ColorTemperature": "0",
+        "ExposureTime": 0.01,
+        "ISO": "100",
+        "TriggerDelay": 0
+    });
+    [
+        ("auth", status()),
+        ("get_status", status()),
+        (
+            "get_upf_infos",
+            json!({
+                "is_full": false,
+                "upf_infos": [{
+                    "capture_date": "2017-06-03T14:20:51Z",
+                    "image_id": "4fd70dfc074340296cc2ebb92158a18d",
+                    "preview_url": "http://127.0.0.1:1/previews/4fd70dfc074340296cc2ebb92158a18d.jpg",
+                    "size": 117964800,
+                    "upf_url": "http://127.0.0.1:1/upfs/4fd70dfc074340296cc2ebb92158a18d.upf"
+                }]
+            }),
+        ),
+        ("get_options", json!({ "options": options })),
+        (
+            "get_option_list",
+            json!({"options": [
+                {
+                    "constraints": [{"constraint": "values", "value": [true, false]}],
+                    "name": "AutoExposure",
+                    "type": "Boolean"
+                },
+                {
+                    "constraints": [{"constraint": "values", "value": ["0", "3000", "4500", "5500", "6500", "8000"]}],
+                    "name": "ColorTemperature",
+                    "type": "Enumeration"
+                },
+                {
+                    "constraints": [
+                        {"constraint": "min", "value": "0.25"},
+                        {"constraint": "max", "value": "2000"}
+                    ],
+                    "name": "ExposureTime",
+                    "type": "Number"
+                },
+                {
+                    "constraints": [{"constraint": "values", "value": ["50", "100", "200", "400", "800"]}],
+                    "name": "ISO",
+                    "type": "Enumeration"
+                },
+                {
+                    "constraints": [
+                        {"constraint": "min", "value": 0},
+                        {"constraint": "max", "value": 10000}
+                    ],
+                    "name": "TriggerDelay",
+                    "type": "Integer"
+                }
+            ]}),
+        ),
+        ("get_option", json!({"name": "ISO", "value": "100"})),
+        ("set_option", json!({})),
+        ("delete_upf", json!({"panorama": true, "preview": true})),
+        (
+            "capture",
+            json!({"capture_available": false, "options": options}),
+        ),
+    ]
+    .into_iter()
+    .map(|(method, result)| (method.to_string(), Reply::Result(result)))
+    .collect()
+}
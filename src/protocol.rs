@@ -0,0 +1,1158 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "method", content = "params")]
+pub enum Method {
+    Auth {
+        device: String,
+        force: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        auth_token: Option<String>,
+    },
+    DeleteUpf {
+        image_id: String,
+    },
+    GetUpfInfos,
+    GetStatus,
+    GetOptions,
+    GetOptionList,
+    GetOption {
+        name: KnownOption,
+    },
+    SetOption {
+        name: KnownOption,
+        value: StringOrNumber,
+    },
+    Capture,
+    /// `capture` with some settings overridden. A separate variant so a plain capture still goes
+    /// out without params.
+    #[serde(rename = "capture")]
+    CaptureWith {
+        options: CaptureOptions,
+    },
+}
+
+/// Name of a camera option. The options this tool knows about have their own variants, anything
+/// else the camera reports is passed through as `Other`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum KnownOption {
+    AutoExposure,
+    ColorTemperature,
+    ExposureTime,
+    Iso,
+    TriggerDelay,
+    Other(String),
+}
+
+impl KnownOption {
+    const KNOWN: [KnownOption; 5] = [
+        KnownOption::AutoExposure,
+        KnownOption::ColorTemperature,
+        KnownOption::ExposureTime,
+        KnownOption::Iso,
+        KnownOption::TriggerDelay,
+    ];
+
+    /// The name as the camera spells it
+    pub fn as_str(&self) -> &str {
+        match self {
+            KnownOption::AutoExposure => "AutoExposure",
+            KnownOption::ColorTemperature => "ColorTemperature",
+            KnownOption::ExposureTime => "ExposureTime",
+            KnownOption::Iso => "ISO",
+            KnownOption::TriggerDelay => "TriggerDelay",
+            KnownOption::Other(name) => name,
+        }
+    }
+}
+
+impl From<String> for KnownOption {
+    fn from(name: String) -> Self {
+        KnownOption::KNOWN
+            .into_iter()
+            .find(|o| o.as_str() == name)
+            .unwrap_or(KnownOption::Other(name))
+    }
+}
+
+impl From<KnownOption> for String {
+    fn from(option: KnownOption) -> Self {
+        option.as_str().to_string()
+    }
+}
+
+/// Parses user input, so known names are matched regardless of case and underscores or dashes,
+/// e.g. `iso` is `ISO` and `auto_exposure` is `AutoExposure`. The short names used by the capture
+/// flags, `color_temp` and `exposure`, work too.
+impl std::str::FromStr for KnownOption {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let normalized = s.replace(['_', '-'], "").to_ascii_lowercase();
+        let alias = match normalized.as_str() {
+            "colortemp" => Some(KnownOption::ColorTemperature),
+            "exposure" => Some(KnownOption::ExposureTime),
+            _ => None,
+        };
+        Ok(alias
+            .or_else(|| {
+                KnownOption::KNOWN
+                    .into_iter()
+                    .find(|o| o.as_str().eq_ignore_ascii_case(&normalized))
+            })
+            .unwrap_or_else(|| KnownOption::Other(s.to_string())))
+    }
+}
+
+impl std::fmt::Display for KnownOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A request's method name and its params, already serialized
+#[derive(Debug, Serialize)]
+pub(crate) struct RawMethod<'a> {
+    pub(crate) method: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) params: Option<&'a RawValue>,
+}
+
+/// JSON-RPC version sent with every request and expected in every packet from the camera
+pub const JSONRPC_VERSION: &str = "2.0";
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Request<'m, M = Method> {
+    pub(crate) id: u32,
+    #[serde(flatten)]
+    pub(crate) method: &'m M,
+    pub(crate) jsonrpc: &'static str,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct IncomingRequest<'r> {
+    pub(crate) jsonrpc: String,
+    pub(crate) method: String,
+    #[serde(borrow)]
+    pub(crate) params: &'r RawValue,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Response<'r> {
+    pub(crate) id: u32,
+    pub(crate) jsonrpc: String,
+    /// Some firmware leaves this out or sends null even when the request succeeded
+    #[serde(borrow, default)]
+    pub(crate) result: Option<&'r RawValue>,
+    pub(crate) warning: Option<ResponseWarning>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ErrorResponse {
+    pub(crate) id: u32,
+    pub(crate) jsonrpc: String,
+    pub(crate) error: ResponseError,
+}
+
+// {"error":{"code":309,"details":{"panorama":{"message":"no_panorama","sender":"delete_upf"},"preview":{"message":"no_preview","sender":"delete_upf"}},"request":{"id":3,"jsonrpc":"2.0","method":"delete_upf","params":{"image_id":"4fd70dfc074340296cc2ebb92158a18d"}}},"id":3,"jsonrpc":"2.0"}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseError {
+    pub code: u32,
+    pub message: Option<String>,
+    #[serde(default)]
+    pub details: HashMap<String, ErrorDetail>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    pub message: String,
+    pub sender: Option<String>,
+}
+
+impl std::fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "camera returned error {}", self.code)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+        let mut details = self.details.iter().collect::<Vec<_>>();
+        details.sort_by_key(|(k, _)| *k);
+        for (key, detail) in details {
+            write!(f, "\n  {key}: {}", detail.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+impl ResponseError {
+    /// Explain error 309 from `delete_upf`, whose details say separately why the panorama and the
+    /// preview could not be deleted
+    pub fn delete_failure(&self) -> Option<String> {
+        if self.code != 309 {
+            return None;
+        }
+        let parts = ["panorama", "preview"]
+            .into_iter()
+            .filter_map(|part| Some((part, &self.details.get(part)?.message)))
+            .collect::<Vec<_>>();
+        if parts.is_empty() {
+            return None;
+        }
+        let reasons = if parts.iter().all(|(part, m)| **m == format!("no_{part}")) {
+            let missing = parts.iter().map(|(part, _)| *part).collect::<Vec<_>>();
+            vec![format!(
+                "the camera has no {} with that ID",
+                missing.join(" or ")
+            )]
+        } else {
+            parts
+                .iter()
+                .map(|(part, message)| format!("the {part} could not be deleted ({message})"))
+                .collect()
+        };
+        Some(format!("nothing was deleted, {}", reasons.join(" and ")))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseWarning {
+    pub code: u32,
+    pub message: String,
+}
+
+impl std::fmt::Display for ResponseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "camera warning {}: {}", self.code, self.message)
+    }
+}
+
+/// Point in time as reported by the camera, keeping the offset it was given in
+pub type Timestamp = DateTime<FixedOffset>;
+
+/// Format a timestamp the way the camera does, e.g. `2017-06-03T14:20:51Z`
+pub fn format_timestamp(timestamp: &Timestamp) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// Serde adapter for RFC 3339 timestamps that writes them back out in the camera's own format, so
+/// responses round-trip unchanged
+mod rfc3339 {
+    use super::{format_timestamp, Timestamp};
+    use chrono::DateTime;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(timestamp: &Timestamp, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format_timestamp(timestamp))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Timestamp, D::Error> {
+        let s = String::deserialize(d)?;
+        DateTime::parse_from_rfc3339(&s)
+            .map_err(|e| D::Error::custom(format!("invalid timestamp {s:?}: {e}")))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseStatus {
+    pub auth_token: String,
+    pub capture_available: bool,
+    #[serde(with = "rfc3339")]
+    pub current_time: Timestamp,
+    pub device_id: String,
+    pub firmware_update_url: String,
+    pub firmware_version: String,
+    pub is_auth: bool,
+    pub serial_number: String,
+    pub storage: HashMap<String, Storage>,
+    pub update_ready: bool,
+    /// Not reported by older firmware
+    pub battery_level: Option<u8>,
+    /// Not reported by older firmware
+    pub is_charging: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusUpdate {
+    pub capture_available: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseGetUpfInfos {
+    pub is_full: bool,
+    pub upf_infos: Vec<UpfInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseGetOptionList {
+    pub options: Vec<CameraOption>,
+}
+
+impl ResponseGetOptionList {
+    pub const TYPE_NAMES: [&'static str; 4] = ["Boolean", "Enumeration", "Number", "Integer"];
+
+    /// Keep only options of the given type, e.g. `Enumeration`, matched case-insensitively
+    pub fn retain_type(&mut self, type_name: &str) -> Result<()> {
+        if !Self::TYPE_NAMES
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(type_name))
+        {
+            bail!(
+                "Unknown option type {type_name:?}, expected one of {}",
+                Self::TYPE_NAMES.join(", ")
+            );
+        }
+        self.options
+            .retain(|o| o.type_name().eq_ignore_ascii_case(type_name));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum CameraOption {
+    Boolean {
+        name: String,
+        constraints: Vec<Constraint<bool>>,
+    },
+    Enumeration {
+        name: String,
+        constraints: Vec<Constraint<String>>,
+    },
+    Number {
+        name: String,
+        constraints: Vec<Constraint<String>>,
+    },
+    Integer {
+        name: String,
+        constraints: Vec<Constraint<u64>>,
+    },
+}
+
+impl CameraOption {
+    pub fn name(&self) -> &str {
+        match self {
+            CameraOption::Boolean { name, .. }
+            | CameraOption::Enumeration { name, .. }
+            | CameraOption::Number { name, .. }
+            | CameraOption::Integer { name, .. } => name,
+        }
+    }
+
+    /// The variant name, as the camera sends it in the `type` field
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            CameraOption::Boolean { .. } => "Boolean",
+            CameraOption::Enumeration { .. } => "Enumeration",
+            CameraOption::Number { .. } => "Number",
+            CameraOption::Integer { .. } => "Integer",
+        }
+    }
+
+    /// Parse user input into the value type expected by this option and check it against the
+    /// option's constraints
+    pub fn parse_value(&self, input: &str) -> Result<StringOrNumber> {
+        match self {
+            CameraOption::Boolean { constraints, .. } => {
+                let value: bool = input
+                    .parse()
+                    .with_context(|| format!("{} expects true or false", self.name()))?;
+                check_constraints(self.name(), constraints, &value)?;
+                Ok(StringOrNumber::Bool(value))
+            }
+            CameraOption::Enumeration { constraints, .. } => {
+                let value = input.to_string();
+                check_constraints(self.name(), constraints, &value)?;
+                Ok(StringOrNumber::String(value))
+            }
+            CameraOption::Number { constraints, .. } => {
+                let value = parse_number(input).with_context(|| {
+                    format!("{} expects a number or a fraction like 1/100", self.name())
+                })?;
+                let constraints = constraints
+                    .iter()
+                    .map(|c| c.try_map(|v| v.parse::<f64>()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("Invalid constraints for {}", self.name()))?;
+                check_constraints(self.name(), &constraints, &value)?;
+                Ok(StringOrNumber::Number(value))
+            }
+            CameraOption::Integer { constraints, .. } => {
+                let value: u64 = input
+                    .parse()
+                    .with_context(|| format!("{} expects an integer", self.name()))?;
+                check_constraints(self.name(), constraints, &value)?;
+                Ok(StringOrNumber::Number(value as f64))
+            }
+        }
+    }
+
+    /// Values allowed by a `values` constraint, in the order the camera lists them
+    pub fn allowed_values(&self) -> Option<Vec<StringOrNumber>> {
+        fn values<T: Clone>(
+            constraints: &[Constraint<T>],
+            f: impl Fn(T) -> StringOrNumber,
+        ) -> Option<Vec<StringOrNumber>> {
+            constraints.iter().find_map(|c| match c {
+                Constraint::Values { value } => Some(value.iter().cloned().map(&f).collect()),
+                _ => None,
+            })
+        }
+        match self {
+            CameraOption::Boolean { constraints, .. } => values(constraints, StringOrNumber::Bool),
+            CameraOption::Enumeration { constraints, .. }
+            | CameraOption::Number { constraints, .. } => {
+                values(constraints, StringOrNumber::String)
+            }
+            CameraOption::Integer { constraints, .. } => {
+                values(constraints, |v| StringOrNumber::Number(v as f64))
+            }
+        }
+    }
+
+    /// The allowed value after `current`, or before it if `up` is false
+    pub fn step_value(&self, current: &StringOrNumber, up: bool) -> Result<StringOrNumber> {
+        let values = self.allowed_values().with_context(|| {
+            format!(
+                "{} has no list of allowed values to step through",
+                self.name()
+            )
+        })?;
+        let list = || {
+            values
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let index = values
+            .iter()
+            .position(|v| v.same_value(current))
+            .with_context(|| {
+                format!(
+                    "Current value {current} of {} is not one of {}",
+                    self.name(),
+                    list()
+                )
+            })?;
+        let next = if up {
+            values.get(index + 1)
+        } else {
+            index.checked_sub(1).and_then(|i| values.get(i))
+        };
+        next.cloned().with_context(|| {
+            format!(
+                "{} is already at its {} value {current}",
+                self.name(),
+                if up { "last" } else { "first" }
+            )
+        })
+    }
+}
+
+/// Parse a number, also accepting fractions like the 1/100 shutter speed notation
+pub fn parse_number(input: &str) -> Result<f64> {
+    let value = match input.split_once('/') {
+        Some((n, d)) => n.trim().parse::<f64>()? / d.trim().parse::<f64>()?,
+        None => input.trim().parse()?,
+    };
+    if !value.is_finite() {
+        bail!("{input} is not a finite number");
+    }
+    Ok(value)
+}
+
+fn check_constraints<T: Debug + PartialOrd>(
+    name: &str,
+    constraints: &[Constraint<T>],
+    value: &T,
+) -> Result<()> {
+    for constraint in constraints {
+        match constraint {
+            Constraint::Values { value: values } => {
+                if !values.contains(value) {
+                    bail!("{name} must be one of {values:?}, got {value:?}");
+                }
+            }
+            Constraint::Min { value: min } => {
+                if value < min {
+                    bail!("{name} must be at least {min:?}, got {value:?}");
+                }
+            }
+            Constraint::Max { value: max } => {
+                if value > max {
+                    bail!("{name} must be at most {max:?}, got {value:?}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "constraint", rename_all = "snake_case")]
+pub enum Constraint<T> {
+    Values { value: Vec<T> },
+    Min { value: T },
+    Max { value: T },
+}
+
+impl<T> Constraint<T> {
+    fn try_map<U, E>(&self, f: impl Fn(&T) -> Result<U, E>) -> Result<Constraint<U>, E> {
+        Ok(match self {
+            Constraint::Values { value } => Constraint::Values {
+                value: value.iter().map(f).collect::<Result<_, _>>()?,
+            },
+            Constraint::Min { value } => Constraint::Min { value: f(value)? },
+            Constraint::Max { value } => Constraint::Max { value: f(value)? },
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseGetOption {
+    pub name: KnownOption,
+    pub value: StringOrNumber,
+}
+
+/// Current value of every option, keyed by name
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseGetOptions {
+    pub options: BTreeMap<String, StringOrNumber>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StringOrNumber {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+impl StringOrNumber {
+    /// Whether both are the same value, treating numbers sent as strings like the numbers
+    /// themselves since the camera is not consistent about it
+    pub fn same_value(&self, other: &StringOrNumber) -> bool {
+        let number = |v: &StringOrNumber| match v {
+            StringOrNumber::Number(n) => Some(*n),
+            StringOrNumber::String(s) => s.parse::<f64>().ok(),
+            StringOrNumber::Bool(_) => None,
+        };
+        match (number(self), number(other)) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.to_string() == other.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for StringOrNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StringOrNumber::String(s) => write!(f, "{s}"),
+            StringOrNumber::Number(n) => write!(f, "{n}"),
+            StringOrNumber::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseDelete {
+    pub panorama: bool,
+    pub preview: bool,
+}
+
+/// Which of the two files were deleted, a `false` meaning it was already gone
+impl std::fmt::Display for ResponseDelete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.panorama, self.preview) {
+            (true, true) => write!(f, "deleted panorama and preview"),
+            (true, false) => write!(f, "deleted panorama, preview already gone"),
+            (false, true) => write!(f, "panorama already gone, deleted preview"),
+            (false, false) => write!(f, "panorama and preview already gone"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseCapture {
+    pub capture_available: bool,
+    pub options: CaptureOptions,
+}
+
+/// Settings a capture was taken with. When passed to `Method::CaptureWith`, fields left as `None`
+/// keep the camera's current setting.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CaptureOptions {
+    #[serde(rename = "AutoExposure", skip_serializing_if = "Option::is_none")]
+    pub auto_exposure: Option<bool>,
+    #[serde(rename = "ColorTemperature", skip_serializing_if = "Option::is_none")]
+    pub color_temperature: Option<String>,
+    #[serde(rename = "ExposureTime", skip_serializing_if = "Option::is_none")]
+    pub exposure_time: Option<f64>,
+    #[serde(rename = "ISO", skip_serializing_if = "Option::is_none")]
+    pub iso: Option<String>,
+    #[serde(rename = "TriggerDelay", skip_serializing_if = "Option::is_none")]
+    pub trigger_delay: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpfInfo {
+    #[serde(with = "rfc3339")]
+    pub capture_date: Timestamp,
+    pub image_id: String,
+    pub preview_url: String,
+    pub size: u64,
+    pub upf_url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Storage {
+    pub total: u64,
+    pub usage: u64,
+}
+
+#[derive(Debug)]
+pub(crate) enum PacketIncoming<'a> {
+    Response(Response<'a>),
+    Error(ErrorResponse),
+    IncomingRequest(IncomingRequest<'a>),
+}
+
+impl PacketIncoming<'_> {
+    /// The JSON-RPC version the packet declares
+    pub(crate) fn jsonrpc(&self) -> &str {
+        match self {
+            PacketIncoming::Response(r) => &r.jsonrpc,
+            PacketIncoming::Error(e) => &e.jsonrpc,
+            PacketIncoming::IncomingRequest(r) => &r.jsonrpc,
+        }
+    }
+}
+
+/// Reassembles packets split across websocket frames. Packets are separated by newlines and a frame
+/// may end partway through one, in which case the rest arrives with the next frame.
+#[derive(Debug, Default)]
+pub(crate) struct PacketBuffer {
+    partial: String,
+}
+
+impl PacketBuffer {
+    /// Add a frame, returning the packets it completes
+    pub(crate) fn push(&mut self, text: &str) -> Vec<String> {
+        let mut buffer = std::mem::take(&mut self.partial);
+        buffer.push_str(text);
+        let mut packets = vec![];
+        let mut lines = buffer.lines().filter(|l| !l.trim().is_empty()).peekable();
+        while let Some(line) = lines.next() {
+            let last = lines.peek().is_none() && !buffer.ends_with('\n');
+            if last && is_incomplete(line) {
+                self.partial = line.to_string();
+            } else {
+                packets.push(line.to_string());
+            }
+        }
+        packets
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.partial.clear();
+    }
+}
+
+/// Whether the text is the start of a JSON value that hasn't been fully received yet
+fn is_incomplete(text: &str) -> bool {
+    matches!(serde_json::from_str::<serde::de::IgnoredAny>(text), Err(e) if e.is_eof())
+}
+
+pub(crate) fn parse_packet(text: &str) -> Result<PacketIncoming<'_>> {
+    // errors first, since with `result` optional they would also parse as a response
+    serde_json::from_str(text)
+        .map(PacketIncoming::Error)
+        .or_else(|_| serde_json::from_str(text).map(PacketIncoming::Response))
+        .or_else(|_| serde_json::from_str(text).map(PacketIncoming::IncomingRequest))
+        .with_context(|| format!("Error parsing packet {}", &text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn options() {
+        let mut list = serde_json::from_str::<ResponseGetOptionList>(
+            r#"{
+            "options": [{
+                    "constraints": [{
+                        "constraint": "values",
+                        "value": [true, false]
+                    }],
+                    "name": "AutoExposure",
+                    "type": "Boolean"
+                },
+                {
+                    "constraints": [{
+                        "constraint": "values",
+                        "value": ["0", "3000", "4500", "5500", "6500", "8000"]
+                    }],
+                    "name": "ColorTemperature",
+                    "type": "Enumeration"
+                },
+                {
+                    "constraints": [{
+                            "constraint": "min",
+                            "value": "0.25"
+                        },
+                        {
+                            "constraint": "max",
+                            "value": "2000"
+                        }
+                    ],
+                    "name": "ExposureTime",
+                    "type": "Number"
+                },
+                {
+                    "constraints": [{
+                        "constraint": "values",
+                        "value": ["50", "100", "200", "400", "800"]
+                    }],
+                    "name": "ISO",
+                    "type": "Enumeration"
+                },
+                {
+                    "constraints": [{
+                            "constraint": "min",
+                            "value": 0
+                        },
+                        {
+                            "constraint": "max",
+                            "value": 10000
+                        }
+                    ],
+                    "name": "TriggerDelay",
+                    "type": "Integer"
+                }
+            ]
+        }"#,
+        )
+        .unwrap();
+
+        list.retain_type("enumeration").unwrap();
+        let names = list.options.iter().map(|o| o.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["ColorTemperature", "ISO"]);
+        assert!(list.retain_type("Float").is_err());
+    }
+
+    #[test]
+    fn constraint() {
+        serde_json::from_str::<CameraOption>(
+            r#"{
+            "constraints": [{
+                "constraint": "values",
+                "value": [true, false]
+            }],
+            "name": "AutoExposure",
+            "type": "Boolean"
+        }"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn step_value() {
+        let option = serde_json::from_str::<CameraOption>(
+            r#"{
+            "constraints": [{
+                "constraint": "values",
+                "value": ["100", "200", "400", "800"]
+            }],
+            "name": "ISO",
+            "type": "Enumeration"
+        }"#,
+        )
+        .unwrap();
+        let step =
+            |current: StringOrNumber, up| option.step_value(&current, up).map(|v| v.to_string());
+        assert_eq!(
+            step(StringOrNumber::String("200".into()), true).unwrap(),
+            "400"
+        );
+        assert_eq!(
+            step(StringOrNumber::String("200".into()), false).unwrap(),
+            "100"
+        );
+        assert_eq!(step(StringOrNumber::Number(400.), true).unwrap(), "800");
+        assert!(step(StringOrNumber::String("800".into()), true).is_err());
+        assert!(step(StringOrNumber::String("100".into()), false).is_err());
+        assert!(step(StringOrNumber::String("auto".into()), true).is_err());
+
+        let option = serde_json::from_str::<CameraOption>(
+            r#"{
+            "constraints": [{"constraint": "min", "value": 0}],
+            "name": "TriggerDelay",
+            "type": "Integer"
+        }"#,
+        )
+        .unwrap();
+        assert!(option
+            .step_value(&StringOrNumber::Number(0.), true)
+            .is_err());
+    }
+
+    #[test]
+    fn parse_option_value() {
+        let option = serde_json::from_str::<CameraOption>(
+            r#"{
+            "constraints": [{
+                    "constraint": "min",
+                    "value": "0.25"
+                },
+                {
+                    "constraint": "max",
+                    "value": "2000"
+                }
+            ],
+            "name": "ExposureTime",
+            "type": "Number"
+        }"#,
+        )
+        .unwrap();
+        assert!(matches!(option.parse_value("100"), Ok(StringOrNumber::Number(v)) if v == 100.));
+        assert!(matches!(option.parse_value("0.25"), Ok(StringOrNumber::Number(v)) if v == 0.25));
+        assert!(matches!(option.parse_value("2000"), Ok(StringOrNumber::Number(v)) if v == 2000.));
+        assert!(matches!(option.parse_value("1/2"), Ok(StringOrNumber::Number(v)) if v == 0.5));
+        assert!(option.parse_value("0.1").is_err());
+        assert!(option.parse_value("1/100").is_err());
+        assert!(option.parse_value("2001").is_err());
+        assert!(option.parse_value("1/0").is_err());
+        assert!(option.parse_value("abc").is_err());
+
+        let option = serde_json::from_str::<CameraOption>(
+            r#"{
+            "constraints": [{
+                "constraint": "values",
+                "value": ["50", "100", "200", "400", "800"]
+            }],
+            "name": "ISO",
+            "type": "Enumeration"
+        }"#,
+        )
+        .unwrap();
+        assert!(matches!(option.parse_value("400"), Ok(StringOrNumber::String(v)) if v == "400"));
+        assert!(option.parse_value("300").is_err());
+    }
+
+    #[test]
+    fn error_response() {
+        let res = serde_json::from_str::<ErrorResponse>(
+            r#"{"error":{"code":309,"details":{"panorama":{"message":"no_panorama","sender":"delete_upf"},"preview":{"message":"no_preview","sender":"delete_upf"}},"request":{"id":3,"jsonrpc":"2.0","method":"delete_upf","params":{"image_id":"4fd70dfc074340296cc2ebb92158a18d"}}},"id":3,"jsonrpc":"2.0"}"#,
+        )
+        .unwrap();
+        assert_eq!(res.id, 3);
+        assert_eq!(res.error.code, 309);
+        assert_eq!(res.error.details["panorama"].message, "no_panorama");
+        assert_eq!(
+            res.error.to_string(),
+            "camera returned error 309\n  panorama: no_panorama\n  preview: no_preview"
+        );
+        assert_eq!(
+            res.error.delete_failure().unwrap(),
+            "nothing was deleted, the camera has no panorama or preview with that ID"
+        );
+    }
+
+    #[test]
+    fn status_update() {
+        let text =
+            r#"{"jsonrpc":"2.0","method":"status_update","params":{"capture_available":false}}"#;
+        let PacketIncoming::IncomingRequest(req) = parse_packet(text).unwrap() else {
+            panic!("expected notification");
+        };
+        assert_eq!(req.method, "status_update");
+        let update: StatusUpdate = serde_json::from_str(req.params.get()).unwrap();
+        assert_eq!(update.capture_available, Some(false));
+    }
+
+    #[test]
+    fn raw_request() {
+        let params: &RawValue = serde_json::from_str(r#"{"name": "Foo"}"#).unwrap();
+        let serialize = |params| {
+            serde_json::to_string(&Request {
+                id: 1,
+                method: &RawMethod {
+                    method: "get_option",
+                    params,
+                },
+                jsonrpc: JSONRPC_VERSION,
+            })
+            .unwrap()
+        };
+        assert_eq!(
+            serialize(Some(params)),
+            r#"{"id":1,"method":"get_option","params":{"name": "Foo"},"jsonrpc":"2.0"}"#
+        );
+        assert_eq!(
+            serialize(None),
+            r#"{"id":1,"method":"get_option","jsonrpc":"2.0"}"#
+        );
+    }
+
+    #[test]
+    fn get_options() {
+        let res = serde_json::from_str::<ResponseGetOptions>(
+            r#"{
+            "options": {
+                "AutoExposure": true,
+                "ColorTemperature": "auto",
+                "ExposureTime": 0.01,
+                "ISO": "100",
+                "TriggerDelay": 0
+            }
+        }"#,
+        )
+        .unwrap();
+        assert_eq!(res.options.len(), 5);
+        assert!(matches!(
+            res.options["AutoExposure"],
+            StringOrNumber::Bool(true)
+        ));
+        assert!(matches!(&res.options["ISO"], StringOrNumber::String(s) if s == "100"));
+    }
+
+    #[test]
+    fn known_option() {
+        let method = Method::SetOption {
+            name: KnownOption::Iso,
+            value: StringOrNumber::String("100".into()),
+        };
+        assert_eq!(
+            serde_json::to_string(&method).unwrap(),
+            r#"{"method":"set_option","params":{"name":"ISO","value":"100"}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<KnownOption>(r#""ColorTemperature""#).unwrap(),
+            KnownOption::ColorTemperature
+        );
+        assert_eq!(
+            serde_json::from_str::<KnownOption>(r#""Foo""#).unwrap(),
+            KnownOption::Other("Foo".into())
+        );
+        assert_eq!("iso".parse::<KnownOption>().unwrap(), KnownOption::Iso);
+        assert_eq!(
+            "auto_exposure".parse::<KnownOption>().unwrap(),
+            KnownOption::AutoExposure
+        );
+        assert_eq!(
+            "color-temp".parse::<KnownOption>().unwrap(),
+            KnownOption::ColorTemperature
+        );
+        assert_eq!(
+            "foo".parse::<KnownOption>().unwrap(),
+            KnownOption::Other("foo".into())
+        );
+    }
+
+    #[test]
+    fn response_warning() {
+        let text = r#"{"id":4,"jsonrpc":"2.0","result":{},"warning":{"code":201,"message":"low_battery"}}"#;
+        let PacketIncoming::Response(res) = parse_packet(text).unwrap() else {
+            panic!("expected response");
+        };
+        assert_eq!(
+            res.warning.unwrap().to_string(),
+            "camera warning 201: low_battery"
+        );
+    }
+
+    #[test]
+    fn jsonrpc_version() {
+        for (text, version) in [
+            (r#"{"id":1,"jsonrpc":"2.0","result":{}}"#, "2.0"),
+            (r#"{"id":1,"jsonrpc":"1.0","error":{"code":309}}"#, "1.0"),
+            (
+                r#"{"jsonrpc":"2.1","method":"status_update","params":{}}"#,
+                "2.1",
+            ),
+        ] {
+            assert_eq!(parse_packet(text).unwrap().jsonrpc(), version);
+        }
+    }
+
+    #[test]
+    fn response_without_result() {
+        for text in [
+            r#"{"id":5,"jsonrpc":"2.0"}"#,
+            r#"{"id":5,"jsonrpc":"2.0","result":null}"#,
+        ] {
+            let PacketIncoming::Response(res) = parse_packet(text).unwrap() else {
+                panic!("expected response for {text}");
+            };
+            assert!(res.result.is_none());
+        }
+        let text = r#"{"error":{"code":309,"message":"delete_failed"},"id":5,"jsonrpc":"2.0"}"#;
+        assert!(matches!(
+            parse_packet(text).unwrap(),
+            PacketIncoming::Error(_)
+        ));
+    }
+
+    #[test]
+    fn capture_options() {
+        assert_eq!(
+            serde_json::to_string(&Method::Capture).unwrap(),
+            r#"{"method":"capture"}"#
+        );
+        let method = Method::CaptureWith {
+            options: CaptureOptions {
+                color_temperature: Some("5500".into()),
+                iso: Some("400".into()),
+                exposure_time: Some(0.01),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&method).unwrap(),
+            r#"{"method":"capture","params":{"options":{"ColorTemperature":"5500","ExposureTime":0.01,"ISO":"400"}}}"#
+        );
+    }
+
+    #[test]
+    fn split_packets() {
+        let mut buffer = PacketBuffer::default();
+        assert_eq!(
+            buffer.push(r#"{"id":1,"jsonrpc":"2.0","#),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            buffer.push("\"result\":{}}\n{\"id\":2,"),
+            vec![r#"{"id":1,"jsonrpc":"2.0","result":{}}"#]
+        );
+        assert_eq!(
+            buffer.push(r#""jsonrpc":"2.0","result":{}}"#),
+            vec![r#"{"id":2,"jsonrpc":"2.0","result":{}}"#]
+        );
+        // garbage is passed through so it gets reported instead of silently buffered
+        assert_eq!(buffer.push("nonsense"), vec!["nonsense"]);
+        assert_eq!(buffer.push("{}"), vec!["{}"]);
+    }
+
+    #[test]
+    fn status() {
+        let status = r#"{
+            "auth_token": "b6e5a4c1f0d94c0e",
+            "capture_available": true,
+            "current_time": "2017-06-03T14:21:07Z",
+            "device_id": "a1b2c3d4e5f6",
+            "firmware_update_url": "",
+            "firmware_version": "0.3.2-Apricot.product.412",
+            "is_auth": true,
+            "serial_number": "PN00123456",
+            "storage": {
+                "internal": {"total": 15758000128, "usage": 2147483648}
+            },
+            "update_ready": false
+        }"#;
+        let res = serde_json::from_str::<ResponseStatus>(status).unwrap();
+        assert!(res.capture_available);
+        assert_eq!(res.firmware_version, "0.3.2-Apricot.product.412");
+        assert_eq!(res.storage["internal"].usage, 2147483648);
+        assert_eq!(res.battery_level, None);
+        assert_eq!(
+            res.current_time,
+            DateTime::parse_from_rfc3339("2017-06-03T14:21:07+00:00").unwrap()
+        );
+
+        let status = status.replacen('{', r#"{"battery_level": 64, "is_charging": false,"#, 1);
+        let res = serde_json::from_str::<ResponseStatus>(&status).unwrap();
+        assert_eq!(res.battery_level, Some(64));
+        assert_eq!(res.is_charging, Some(false));
+    }
+
+    #[test]
+    fn upf_infos() {
+        let res = serde_json::from_str::<ResponseGetUpfInfos>(
+            r#"{
+            "is_full": false,
+            "upf_infos": [{
+                "capture_date": "2017-06-03T14:20:51Z",
+                "image_id": "4fd70dfc074340296cc2ebb92158a18d",
+                "preview_url": "http://192.168.80.80:80/previews/4fd70dfc074340296cc2ebb92158a18d.jpg",
+                "size": 117964800,
+                "upf_url": "http://192.168.80.80:80/upfs/4fd70dfc074340296cc2ebb92158a18d.upf"
+            }]
+        }"#,
+        )
+        .unwrap();
+        assert!(!res.is_full);
+        assert_eq!(res.upf_infos.len(), 1);
+        assert_eq!(
+            res.upf_infos[0].image_id,
+            "4fd70dfc074340296cc2ebb92158a18d"
+        );
+        assert_eq!(res.upf_infos[0].size, 117964800);
+        assert_eq!(res.upf_infos[0].capture_date.timestamp(), 1496499651);
+        assert_eq!(
+            serde_json::to_value(&res.upf_infos[0]).unwrap()["capture_date"],
+            "2017-06-03T14:20:51Z"
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        for text in [
+            "2017-06-03T14:20:51Z",
+            "2017-06-03T16:20:51+02:00",
+            "2017-06-03T14:20:51.250Z",
+        ] {
+            let timestamp = DateTime::parse_from_rfc3339(text).unwrap();
+            assert_eq!(format_timestamp(&timestamp), text);
+        }
+        let upf = r#"{"capture_date": "03.06.2017", "image_id": "", "preview_url": "", "size": 0, "upf_url": ""}"#;
+        let e = serde_json::from_str::<UpfInfo>(upf).unwrap_err();
+        assert!(e.to_string().contains("invalid timestamp \"03.06.2017\""));
+    }
+
+    #[test]
+    fn capture() {
+        let res = serde_json::from_str::<ResponseCapture>(
+            r#"{
+            "capture_available": false,
+            "options": {
+                "AutoExposure": true,
+                "ColorTemperature": "auto",
+                "ExposureTime": 0.008,
+                "ISO": "100",
+                "TriggerDelay": 0
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(!res.capture_available);
+        assert_eq!(res.options.auto_exposure, Some(true));
+        assert_eq!(res.options.exposure_time, Some(0.008));
+        assert_eq!(res.options.iso.as_deref(), Some("100"));
+    }
+
+    #[test]
+    fn delete() {
+        let text = r#"{"id":3,"jsonrpc":"2.0","result":{"panorama":true,"preview":true}}"#;
+        let PacketIncoming::Response(res) = parse_packet(text).unwrap() else {
+            panic!("expected response");
+        };
+        let res = serde_json::from_str::<ResponseDelete>(res.result.unwrap().get()).unwrap();
+        assert!(res.panorama && res.preview);
+        assert_eq!(res.to_string(), "deleted panorama and preview");
+
+        let res =
+            serde_json::from_str::<ResponseDelete>(r#"{"panorama":false,"preview":true}"#).unwrap();
+        assert_eq!(res.to_string(), "panorama already gone, deleted preview");
+    }
+}
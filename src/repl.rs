@@ -0,0 +1,375 @@
+use anyhow::{bail, Context, Result};
+use easy_repl::{command::ArgsError, Command, CommandStatus, CriticalError};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    CompletionType, Config, Editor, Helper,
+};
+use std::{cell::RefCell, fs, path::PathBuf, rc::Rc};
+
+use crate::color;
+
+/// Commands whose first argument is an option name
+const OPTION_COMMANDS: &[&str] = &["get_option_value", "set_option", "adjust_option"];
+
+/// Read-eval-print loop over `easy_repl` commands. This drives `rustyline` directly rather than
+/// going through `easy_repl::Repl` so completion can reach beyond command names.
+pub struct Repl<'a> {
+    commands: Vec<(&'static str, Command<'a>)>,
+    /// Alias names and the command words each stands for
+    aliases: Vec<(String, Vec<String>)>,
+    fetch_options: Rc<dyn Fn() -> Result<Vec<String>> + 'a>,
+    prompt: Box<dyn Fn() -> String + 'a>,
+    history: Option<PathBuf>,
+    is_fatal: Box<dyn Fn(&anyhow::Error) -> bool + 'a>,
+}
+
+impl<'a> Repl<'a> {
+    /// `fetch_options` is called the first time an option name is completed
+    pub fn new(fetch_options: impl Fn() -> Result<Vec<String>> + 'a) -> Self {
+        Self {
+            commands: vec![],
+            aliases: vec![],
+            fetch_options: Rc::new(fetch_options),
+            prompt: Box::new(|| "> ".to_string()),
+            history: None,
+            is_fatal: Box::new(|_| false),
+        }
+    }
+
+    /// Load history from `path` on start and save it back on exit
+    pub fn set_history_file(&mut self, path: Option<PathBuf>) {
+        self.history = path;
+    }
+
+    /// Build the prompt shown before each line is read
+    pub fn set_prompt(&mut self, prompt: impl Fn() -> String + 'a) {
+        self.prompt = Box::new(prompt);
+    }
+
+    /// Decide which command errors end the REPL, e.g. losing the connection for good, rather than
+    /// being printed before reading the next line. `CriticalError`s always do.
+    pub fn set_is_fatal(&mut self, is_fatal: impl Fn(&anyhow::Error) -> bool + 'a) {
+        self.is_fatal = Box::new(is_fatal);
+    }
+
+    pub fn add(&mut self, name: &'static str, command: Command<'a>) {
+        self.commands.push((name, command));
+    }
+
+    /// Make `name` run `expansion`, a command followed by any arguments to put before the ones
+    /// typed after the alias, e.g. `dl` for `download --previews`. Redefining an alias replaces it.
+    pub fn add_alias(&mut self, name: &str, expansion: &str) -> Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            bail!("Invalid alias name {name:?}");
+        }
+        if self.command_names("").contains(&name) {
+            bail!("Alias {name:?} would hide the command of the same name");
+        }
+        let words = shell_words::split(expansion)
+            .with_context(|| format!("Invalid command for alias {name:?}"))?;
+        match words.first() {
+            Some(command) if self.commands.iter().any(|(n, _)| n == command) => {}
+            Some(command) => bail!("Alias {name:?} refers to unknown command {command:?}"),
+            None => bail!("Alias {name:?} has no command"),
+        }
+        self.aliases.retain(|(n, _)| n != name);
+        self.aliases.push((name.to_string(), words));
+        Ok(())
+    }
+
+    /// Replace an alias at the start of `words` with the command it stands for
+    fn expand(&self, words: &[&str]) -> Vec<String> {
+        let alias = self
+            .aliases
+            .iter()
+            .find(|(n, _)| words.first() == Some(&n.as_str()));
+        let rest = words.iter().skip(alias.is_some() as usize);
+        alias
+            .map_or(&[][..], |(_, expansion)| expansion)
+            .iter()
+            .cloned()
+            .chain(rest.map(|w| w.to_string()))
+            .collect()
+    }
+
+    /// Run a single command, trying each overload in turn. Aliases and unambiguous prefixes of
+    /// command names are accepted.
+    pub fn run_command(&mut self, name: &str, args: &[&str]) -> Result<CommandStatus> {
+        if self.aliases.iter().any(|(n, _)| n == name) {
+            let words = self.expand(&[&[name], args].concat());
+            let words = words.iter().map(String::as_str).collect::<Vec<_>>();
+            return self.run_command(words[0], &words[1..]);
+        }
+        let name = self.resolve(name)?;
+        let mut usage = vec![];
+        for (_, command) in self.commands.iter_mut().filter(|(n, _)| *n == name) {
+            match command.run(args) {
+                Err(e) if e.is::<ArgsError>() => {
+                    usage.push(format!("  {name} {}", command.args_info.join(" ")))
+                }
+                other => return other,
+            }
+        }
+        bail!("Invalid arguments for {name}. Usage:\n{}", usage.join("\n"))
+    }
+
+    fn resolve(&self, prefix: &str) -> Result<&'static str> {
+        if let Some((name, _)) = self.commands.iter().find(|(n, _)| *n == prefix) {
+            return Ok(name);
+        }
+        let mut candidates = self.command_names(prefix);
+        match candidates.len() {
+            0 => bail!("Unknown command {prefix:?}, use 'help' to see available commands"),
+            1 => Ok(candidates[0]),
+            _ => {
+                candidates.sort();
+                bail!(
+                    "Ambiguous command {prefix:?}, candidates:\n  {}",
+                    candidates.join("\n  ")
+                )
+            }
+        }
+    }
+
+    fn command_names(&self, prefix: &str) -> Vec<&'static str> {
+        let mut names = self
+            .commands
+            .iter()
+            .map(|(n, _)| *n)
+            .chain(["help", "quit", "exit"])
+            .filter(|n| n.starts_with(prefix))
+            .collect::<Vec<_>>();
+        names.dedup();
+        names
+    }
+
+    pub fn help(&self) -> String {
+        let mut entries = self
+            .commands
+            .iter()
+            .map(|(name, cmd)| {
+                (
+                    format!("{name} {}", cmd.args_info.join(" ")),
+                    cmd.description.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        entries.extend(
+            self.aliases.iter().map(|(name, expansion)| {
+                (name.clone(), format!("Alias for {}", expansion.join(" ")))
+            }),
+        );
+        entries.sort();
+        entries.push(("help".into(), "Show this help message".into()));
+        entries.push(("quit".into(), "Quit repl".into()));
+        entries.push(("exit".into(), "Same as quit".into()));
+        let width = entries.iter().map(|(sig, _)| sig.len()).max().unwrap_or(0);
+        let mut help = "Available commands:".to_string();
+        for (sig, desc) in entries {
+            help.push_str(&format!("\n  {sig:width$}  {desc}"));
+        }
+        help
+    }
+
+    /// Read and run commands until the user quits. Errors from individual commands are printed and
+    /// the loop carries on, except for fatal errors and terminal failures which end it.
+    pub fn run(&mut self) -> Result<()> {
+        let config = Config::builder()
+            .completion_type(CompletionType::List)
+            .build();
+        let mut editor = Editor::with_config(config);
+        editor.set_helper(Some(Completion {
+            commands: self
+                .command_names("")
+                .into_iter()
+                .map(String::from)
+                .chain(self.aliases.iter().map(|(n, _)| n.clone()))
+                .collect(),
+            options: RefCell::new(None),
+            fetch_options: self.fetch_options.clone(),
+        }));
+        if let Some(path) = self.history.as_ref().filter(|p| p.exists()) {
+            if let Err(e) = editor.load_history(path) {
+                eprintln!("Failed to load history from {}: {e}", path.display());
+            }
+        }
+
+        let res = loop {
+            let line = match editor.readline(&(self.prompt)()) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => {
+                    eprintln!("CTRL-C");
+                    break Ok(());
+                }
+                Err(ReadlineError::Eof) => break Ok(()),
+                Err(e) => break Err(e).context("Failed to read input"),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line);
+            let args = match shell_words::split(line) {
+                Ok(args) => args,
+                Err(e) => {
+                    eprintln!("{}", color::error(format!("Error: {e}")));
+                    continue;
+                }
+            };
+            let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            let args = self.expand(&args);
+            let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            match self.resolve(args[0]) {
+                Ok("help") => eprintln!("{}", self.help()),
+                Ok("quit" | "exit") => break Ok(()),
+                Ok(name) => match self.run_command(name, &args[1..]) {
+                    Ok(CommandStatus::Done) => {}
+                    Ok(CommandStatus::Quit) => break Ok(()),
+                    Err(e) if e.is::<CriticalError>() || (self.is_fatal)(&e) => break Err(e),
+                    Err(e) => eprintln!("{}", color::error(format!("Error: {e:#}"))),
+                },
+                Err(e) => eprintln!("{}", color::error(format!("{e:#}"))),
+            }
+        };
+
+        if let Some(path) = &self.history {
+            let res = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .map_err(ReadlineError::from)
+                .and_then(|_| editor.save_history(path));
+            if let Err(e) = res {
+                eprintln!("Failed to save history to {}: {e}", path.display());
+            }
+        }
+        res
+    }
+}
+
+struct Completion<'a> {
+    /// Command names and aliases
+    commands: Vec<String>,
+    /// Option names, fetched from the camera on first use
+    options: RefCell<Option<Vec<String>>>,
+    fetch_options: Rc<dyn Fn() -> Result<Vec<String>> + 'a>,
+}
+
+impl Completion<'_> {
+    fn option_names(&self) -> Vec<String> {
+        let mut options = self.options.borrow_mut();
+        if options.is_none() {
+            // leave the cache empty on failure so the next attempt tries again
+            *options = (self.fetch_options)().ok();
+        }
+        options.clone().unwrap_or_default()
+    }
+}
+
+impl Completer for Completion<'_> {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let prefix = &line[start..];
+        let words = line[..start].split_whitespace().collect::<Vec<_>>();
+        let candidates = match words.as_slice() {
+            [] => self.commands.clone(),
+            [command] if OPTION_COMMANDS.contains(command) => self.option_names(),
+            _ => vec![],
+        };
+        let pairs = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(prefix))
+            .map(|c| Pair {
+                display: c.clone(),
+                replacement: c,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for Completion<'_> {
+    type Hint = String;
+}
+
+impl Highlighter for Completion<'_> {}
+
+impl Validator for Completion<'_> {}
+
+impl Helper for Completion<'_> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustyline::history::History;
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let completion = Completion {
+            commands: ["get_option_list", "get_option_value", "set_option", "help"]
+                .map(String::from)
+                .to_vec(),
+            options: RefCell::new(None),
+            fetch_options: Rc::new(|| Ok(vec!["ColorTemperature".into(), "Resolution".into()])),
+        };
+        let history = History::new();
+        let (start, pairs) = completion
+            .complete(line, line.len(), &rustyline::Context::new(&history))
+            .unwrap();
+        (start, pairs.into_iter().map(|p| p.replacement).collect())
+    }
+
+    #[test]
+    fn completion() {
+        assert_eq!(
+            complete("get_opt"),
+            (0, vec!["get_option_list".into(), "get_option_value".into()])
+        );
+        assert_eq!(
+            complete("set_option Co"),
+            (11, vec!["ColorTemperature".into()])
+        );
+        assert_eq!(complete("get_option_value ").1.len(), 2);
+        assert_eq!(complete("get_option_list Co"), (16, vec![]));
+        assert_eq!(complete("set_option Resolution "), (22, vec![]));
+    }
+
+    #[test]
+    fn aliases() {
+        let ran = Rc::new(RefCell::new(vec![]));
+        let mut repl = Repl::new(|| Ok(vec![]));
+        let r = ran.clone();
+        repl.add(
+            "download",
+            Command {
+                description: "Download".into(),
+                args_info: vec!["[--previews]".into()],
+                handler: Box::new(move |args| {
+                    r.borrow_mut().push(args.join(" "));
+                    Ok(CommandStatus::Done)
+                }),
+            },
+        );
+        repl.add_alias("dl", "download --previews").unwrap();
+        repl.run_command("dl", &["--dry-run"]).unwrap();
+        repl.run_command("download", &[]).unwrap();
+        assert_eq!(*ran.borrow(), ["--previews --dry-run", ""]);
+        assert!(repl.help().contains("Alias for download --previews"));
+
+        assert!(repl.add_alias("download", "download").is_err());
+        assert!(repl.add_alias("quit", "download").is_err());
+        assert!(repl.add_alias("ls", "get_upf_infos").is_err());
+        assert!(repl.add_alias("ls", "").is_err());
+        assert!(repl.add_alias("l s", "download").is_err());
+    }
+}
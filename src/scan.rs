@@ -0,0 +1,132 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    str::FromStr,
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+/// Where the camera listens for the websocket
+const PORT: u16 = 12345;
+/// The camera's own address on the WiFi network it hosts, tried before anything else
+const DEFAULT_HOST: Ipv4Addr = Ipv4Addr::new(192, 168, 80, 80);
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How many hosts to probe at once
+const CONCURRENCY: usize = 64;
+
+/// IPv4 network to probe for cameras, from --scan-subnet, e.g. `192.168.80.0/24`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Subnet {
+    network: Ipv4Addr,
+    prefix: u8,
+}
+
+impl FromStr for Subnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix) = s
+            .split_once('/')
+            .with_context(|| format!("Expected a subnet like 192.168.80.0/24, got {s:?}"))?;
+        let address: Ipv4Addr = address
+            .parse()
+            .with_context(|| format!("Invalid address {address:?}"))?;
+        let prefix: u8 = prefix
+            .parse()
+            .with_context(|| format!("Invalid prefix length {prefix:?}"))?;
+        // anything larger would take minutes to probe
+        if !(16..=32).contains(&prefix) {
+            bail!("Prefix length must be between 16 and 32, got {prefix}");
+        }
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        Ok(Self {
+            network: Ipv4Addr::from(u32::from(address) & mask),
+            prefix,
+        })
+    }
+}
+
+impl Subnet {
+    /// Every usable host address, leaving out the network and broadcast addresses where there
+    /// are any
+    fn hosts(&self) -> Vec<Ipv4Addr> {
+        let start = u32::from(self.network);
+        let size = 1u64 << (32 - self.prefix);
+        let range = match size {
+            1 | 2 => 0..size,
+            _ => 1..size - 1,
+        };
+        range.map(|i| Ipv4Addr::from(start + i as u32)).collect()
+    }
+}
+
+fn address(host: Ipv4Addr, port: u16) -> String {
+    format!("ws://{host}:{port}/8086")
+}
+
+fn probe(host: Ipv4Addr, port: u16) -> bool {
+    TcpStream::connect_timeout(&SocketAddr::from((host, port)), PROBE_TIMEOUT).is_ok()
+}
+
+/// Look for cameras without SSDP by checking which hosts have the websocket port open. The
+/// camera's default address is tried first and the rest of `subnet` only if it isn't there.
+pub fn find_cameras(subnet: Option<Subnet>) -> Vec<String> {
+    if probe(DEFAULT_HOST, PORT) {
+        return vec![address(DEFAULT_HOST, PORT)];
+    }
+    match subnet {
+        Some(subnet) => scan(&subnet.hosts(), PORT),
+        None => vec![],
+    }
+}
+
+/// Probe `hosts` concurrently, returning the addresses of those that answered in host order
+fn scan(hosts: &[Ipv4Addr], port: u16) -> Vec<String> {
+    let queue = Mutex::new(hosts.iter());
+    let found = Mutex::new(vec![]);
+    thread::scope(|s| {
+        for _ in 0..CONCURRENCY.min(hosts.len()) {
+            s.spawn(|| loop {
+                let Some(&host) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                if probe(host, port) {
+                    found.lock().unwrap().push(host);
+                }
+            });
+        }
+    });
+    let mut found = found.into_inner().unwrap();
+    found.sort();
+    found.into_iter().map(|host| address(host, port)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subnet() {
+        let subnet = "192.168.80.17/24".parse::<Subnet>().unwrap();
+        let hosts = subnet.hosts();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 80, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 80, 254));
+        assert_eq!(
+            "10.0.0.5/32".parse::<Subnet>().unwrap().hosts(),
+            [Ipv4Addr::new(10, 0, 0, 5)]
+        );
+        for bad in ["192.168.80.0", "192.168.80.0/8", "192.168.80/24", "::1/120"] {
+            assert!(bad.parse::<Subnet>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn scan_loopback() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let hosts = "127.0.0.0/30".parse::<Subnet>().unwrap().hosts();
+        assert_eq!(scan(&hosts, port), [format!("ws://127.0.0.1:{port}/8086")]);
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{bail, Result};
+use chrono::format::{Item, StrftimeItems};
+use panonoctl_rs::Timestamp;
+use std::{
+    path::{Component, Path},
+    str::FromStr,
+};
+
+/// Date format for a plain `{capture_date}`, e.g. `2017-06-03_142051`
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d_%H%M%S";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    ImageId,
+    /// First 8 characters of the image ID, enough to tell captures apart
+    ShortId,
+    CaptureDate(String),
+}
+
+/// How downloaded files are named, from --name-template, without the extension. `{image_id}`,
+/// `{short_id}` and `{capture_date}` are filled in from the UPF, the date with an optional strftime
+/// format as in `{capture_date:%Y/%m/%d}`. Slashes put files in subdirectories of the output
+/// directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        Self {
+            parts: vec![Part::ImageId],
+        }
+    }
+}
+
+impl NameTemplate {
+    pub fn render(&self, image_id: &str, capture_date: &Timestamp) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(s) => s.clone(),
+                Part::ImageId => image_id.to_string(),
+                Part::ShortId => image_id.chars().take(8).collect(),
+                Part::CaptureDate(format) => capture_date.format(format).to_string(),
+            })
+            .collect()
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let Some(len) = rest[start..].find('}') else {
+                bail!("Unclosed {{ in name template {s:?}");
+            };
+            let field = &rest[start + 1..start + len];
+            parts.push(match field.split_once(':') {
+                None if field == "image_id" => Part::ImageId,
+                None if field == "short_id" => Part::ShortId,
+                None if field == "capture_date" => {
+                    Part::CaptureDate(DEFAULT_DATE_FORMAT.to_string())
+                }
+                Some(("capture_date", format)) => {
+                    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                        bail!("Invalid date format {format:?} in name template");
+                    }
+                    Part::CaptureDate(format.to_string())
+                }
+                _ => bail!(
+                    "Unknown field {{{field}}} in name template, expected {{image_id}}, {{short_id}} or {{capture_date}}"
+                ),
+            });
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        // the ID is the only field that tells two captures in the same second apart
+        if !parts
+            .iter()
+            .any(|p| matches!(p, Part::ImageId | Part::ShortId))
+        {
+            bail!("Name template {s:?} needs {{image_id}} or {{short_id}} to keep names unique");
+        }
+        // Fields render to IDs and date numbers, which can't move a path around, so check the
+        // template with each of them standing in as `x`. Literal text in date formats is kept,
+        // since e.g. `{capture_date:../%Y}` would otherwise escape.
+        let skeleton = parts
+            .iter()
+            .map(|p| match p {
+                Part::Literal(s) => s.clone(),
+                Part::ImageId | Part::ShortId => "x".into(),
+                Part::CaptureDate(format) => StrftimeItems::new(format)
+                    .map(|item| match item {
+                        Item::Literal(s) | Item::Space(s) => s.to_string(),
+                        Item::OwnedLiteral(s) | Item::OwnedSpace(s) => s.into(),
+                        _ => "x".into(),
+                    })
+                    .collect(),
+            })
+            .collect::<String>();
+        let path = Path::new(&skeleton);
+        if skeleton.contains('\\')
+            || path.has_root()
+            || path.components().any(|c| c == Component::ParentDir)
+        {
+            bail!("Name template {s:?} must stay inside the output directory");
+        }
+        Ok(Self { parts })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::DateTime;
+
+    #[test]
+    fn render() {
+        let date = DateTime::parse_from_rfc3339("2024-01-15T14:30:22Z").unwrap();
+        let id = "4fd70dfc074340296cc2ebb92158a18d";
+        let render = |template: &str| template.parse::<NameTemplate>().unwrap().render(id, &date);
+
+        assert_eq!(NameTemplate::default().render(id, &date), id);
+        assert_eq!(render("{image_id}"), id);
+        assert_eq!(
+            render("{capture_date}_{short_id}"),
+            "2024-01-15_143022_4fd70dfc"
+        );
+        assert_eq!(
+            render("{capture_date:%Y/%m}/{image_id}"),
+            "2024/01/4fd70dfc074340296cc2ebb92158a18d"
+        );
+
+        for template in [
+            "{capture_date}",
+            "{image_id",
+            "{size}",
+            "{capture_date:%Q}_{image_id}",
+            "../{image_id}",
+            "/tmp/{image_id}",
+            "{capture_date:../%Y}/{image_id}",
+            "{capture_date:%Y/..}/{image_id}",
+            "{capture_date:/%Y}/{image_id}",
+            "..{capture_date:/%Y}/{image_id}",
+            "{capture_date:..\\%Y}/{image_id}",
+        ] {
+            assert!(template.parse::<NameTemplate>().is_err(), "{template}");
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Auth tokens handed out by cameras, keyed by `device_id`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenStore {
+    #[serde(flatten)]
+    devices: HashMap<String, SavedToken>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedToken {
+    /// Address the camera was last reached at, used to pick a token before the device_id is known
+    pub address: String,
+    pub auth_token: String,
+}
+
+impl TokenStore {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("panonoctl").join("token.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().context("Could not determine config directory")?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.save_to(&path)
+    }
+
+    /// Write the tokens to `path`, readable only by the current user since anyone holding a token
+    /// can take over the camera session
+    fn save_to(&self, path: &Path) -> Result<()> {
+        // write alongside and rename over so an interrupted save can't lose the existing tokens
+        let tmp = path.with_extension("json.tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&tmp)
+            .and_then(|mut file| file.write_all(serde_json::to_string_pretty(self)?.as_bytes()))
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn find_by_address(&self, address: &str) -> Option<&SavedToken> {
+        self.devices.values().find(|t| t.address == address)
+    }
+
+    pub fn insert(&mut self, device_id: String, token: SavedToken) {
+        self.devices.insert(device_id, token);
+    }
+
+    /// Drop the token for `device_id`, returning whether there was one
+    pub fn remove(&mut self, device_id: &str) -> bool {
+        self.devices.remove(device_id).is_some()
+    }
+
+    /// Drop any token last used at `address`, returning whether there was one
+    pub fn remove_by_address(&mut self, address: &str) -> bool {
+        let before = self.devices.len();
+        self.devices.retain(|_, t| t.address != address);
+        self.devices.len() != before
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remove() {
+        let mut tokens = TokenStore::default();
+        for (device_id, address) in [
+            ("a1", "ws://192.168.80.80:12345/8086"),
+            ("b2", "ws://10.0.0.2:12345/8086"),
+        ] {
+            tokens.insert(
+                device_id.into(),
+                SavedToken {
+                    address: address.into(),
+                    auth_token: "b6e5a4c1f0d94c0e".into(),
+                },
+            );
+        }
+        assert!(tokens.remove("a1"));
+        assert!(!tokens.remove("a1"));
+        assert!(tokens.remove_by_address("ws://10.0.0.2:12345/8086"));
+        assert!(tokens.find_by_address("ws://10.0.0.2:12345/8086").is_none());
+        assert!(!tokens.remove_by_address("ws://10.0.0.2:12345/8086"));
+    }
+
+    #[test]
+    fn save() {
+        let dir = std::env::temp_dir().join(format!("panonoctl-token-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token.json");
+        // an earlier, world-readable file is replaced rather than written through
+        fs::write(&path, "{}").unwrap();
+
+        let mut tokens = TokenStore::default();
+        tokens.insert(
+            "a1".into(),
+            SavedToken {
+                address: "ws://192.168.80.80:12345/8086".into(),
+                auth_token: "b6e5a4c1f0d94c0e".into(),
+            },
+        );
+        tokens.save_to(&path).unwrap();
+
+        let saved: TokenStore = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert!(saved
+            .find_by_address("ws://192.168.80.80:12345/8086")
+            .is_some());
+        assert!(!dir.join("token.json.tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}